@@ -0,0 +1,185 @@
+// birbfetcher - Collect bird images with ease.
+// Copyright (C) 2020-2021 Mariell Hoversholm
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Compare `ORDER BY RANDOM()` to `Repository::random_image` when picking a
+//! random image, on SQLite databases of growing sizes and with filters matching
+//! fewer and fewer of their images.
+//!
+//! Run with `cargo bench --bench random_selection`.
+
+// We're a binary crate, so the modules the repository needs are compiled into
+// this benchmark as well. Most of what they contain is unused here.
+#![allow(dead_code)]
+
+// Their tests aren't run here either, which leaves their imports unused.
+#[allow(unused_imports)]
+#[path = "../src"]
+mod birbfetcher {
+    pub mod database;
+    pub mod error;
+    pub mod migrations;
+    pub mod utils;
+}
+
+#[allow(unused_imports)]
+mod prelude {
+    pub use crate::database::Repository;
+    pub use crate::error::*;
+    pub use log::{debug, error, info, trace, warn};
+    pub use std::sync::Arc;
+}
+
+use self::birbfetcher::{database, error, migrations, utils};
+use self::database::{Backend, ImageFilter, SqliteRepository};
+use self::migrations::Migrations;
+use self::prelude::*;
+use sqlx::prelude::SqliteQueryAs as _;
+use sqlx::SqlitePool;
+use std::time::{Duration, Instant};
+use strum::IntoEnumIterator as _;
+
+/// The amount of images in each database benchmarked.
+const SIZES: &[u32] = &[1_000, 10_000, 100_000, 1_000_000];
+
+/// The amount of images picked per strategy and size.
+const ITERATIONS: u32 = 100;
+
+/// A filter to pick images with, as given to `random_image` and as SQL.
+struct Case {
+    name: &'static str,
+    filter: fn() -> ImageFilter,
+    conditions: &'static str,
+}
+
+/// The filters benchmarked, from the one used by the random endpoints by
+/// default to ones matching only a few images.
+const CASES: &[Case] = &[
+    Case {
+        name: "verified",
+        filter: || ImageFilter {
+            verified: Some(true),
+            banned: Some(false),
+            ..Default::default()
+        },
+        conditions: "banned = FALSE AND verified = TRUE",
+    },
+    Case {
+        name: "unverified",
+        filter: || ImageFilter {
+            verified: Some(false),
+            banned: Some(false),
+            ..Default::default()
+        },
+        conditions: "banned = FALSE AND verified = FALSE",
+    },
+    Case {
+        name: "rare subreddit",
+        filter: || ImageFilter {
+            subreddit: Some("rarebirbs".into()),
+            banned: Some(false),
+            ..Default::default()
+        },
+        conditions: "banned = FALSE AND subreddit = 'rarebirbs'",
+    },
+];
+
+async fn populate(pool: &SqlitePool, size: u32) -> Result<(), sqlx::Error> {
+    for migration in Migrations::iter() {
+        for query in migration.queries(Backend::Sqlite) {
+            sqlx::query(&query).execute(pool).await?;
+        }
+    }
+
+    // Roughly one in ten images is unverified, one in fifty banned, and one in
+    // a thousand from a rarely posted to subreddit.
+    sqlx::query(
+        r#"
+        INSERT INTO birbs (hash, subreddit, permalink, source_url, content_type, banned, verified)
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < ?)
+        SELECT randomblob(32), CASE WHEN i % 1000 = 7 THEN 'rarebirbs' ELSE 'birbs' END,
+            '/r/birbs', 'https://i.redd.it/', 'image/png', i % 50 = 0, i % 10 <> 0
+        FROM n
+        "#,
+    )
+    .bind(size as i64)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+async fn order_by_random(pool: &SqlitePool, case: &Case) -> Result<(), sqlx::Error> {
+    let sql = format!(
+        "SELECT id FROM birbs WHERE {} ORDER BY RANDOM() LIMIT 1",
+        case.conditions
+    );
+    let _: (i64,) = sqlx::query_as(&sql).fetch_one(pool).await?;
+    Ok(())
+}
+
+async fn random_image(repo: &SqliteRepository, case: &Case) -> Result<(), sqlx::Error> {
+    repo.random_image(&(case.filter)())
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+    Ok(())
+}
+
+async fn measure<F, Fut>(pick: F) -> Result<Duration, sqlx::Error>
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = Result<(), sqlx::Error>>,
+{
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        pick().await?;
+    }
+    Ok(start.elapsed() / ITERATIONS)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), sqlx::Error> {
+    println!(
+        "{:>10} {:>16} {:>20} {:>20}",
+        "images", "filter", "ORDER BY RANDOM()", "random_image"
+    );
+
+    for &size in SIZES {
+        let path = std::env::temp_dir().join(format!(
+            "birbfetcher-bench-{}-{}.db",
+            std::process::id(),
+            size
+        ));
+        std::fs::File::create(&path)?;
+        let url = format!("sqlite://{}", path.display());
+        let pool = SqlitePool::new(&url).await?;
+        populate(&pool, size).await?;
+        let repo = SqliteRepository::connect(&url).await?;
+
+        for case in CASES {
+            let random = measure(|| order_by_random(&pool, case)).await?;
+            let probing = measure(|| random_image(&repo, case)).await?;
+            println!(
+                "{:>10} {:>16} {:>20?} {:>20?}",
+                size, case.name, random, probing
+            );
+        }
+
+        pool.close().await;
+        drop(repo);
+        std::fs::remove_file(&path)?;
+    }
+
+    Ok(())
+}
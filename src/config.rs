@@ -0,0 +1,245 @@
+// birbfetcher - Collect bird images with ease.
+// Copyright (C) 2020-2021 Mariell Hoversholm
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::prelude::*;
+use crate::reddit::{PostType, RedditPost};
+use crate::scheduler::Schedule;
+use anyhow::{Context as _, Result};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::env;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::Duration;
+
+/// The file the configuration is read from unless set by `CONFIG_FILE`.
+const CONFIG_FILE: &str = "birbfetcher.toml";
+
+/// The configuration currently in use, replaced on every reload.
+static CONFIG: Lazy<RwLock<Arc<Config>>> = Lazy::new(Default::default);
+
+/// The configuration of which posts to fetch, and when.
+#[derive(Debug, Default)]
+pub struct Config {
+    pub subreddits: Vec<Subreddit>,
+
+    /// The schedules of jobs by their name, where they differ from the
+    /// defaults.
+    pub jobs: BTreeMap<String, Schedule>,
+}
+
+/// A subreddit to fetch posts from, along with how to treat them.
+///
+/// In the configuration file, these are tables by the name of the subreddit:
+///
+/// ```toml
+/// [subreddits.birbs]
+/// listings = ["hot", "new", "top/week"]
+/// min_score = 10
+/// auto_verify = false
+/// flairs = ["Photo"]
+/// content_types = ["image/jpeg", "image/png"]
+/// interval = 600
+/// ```
+///
+/// Every key is optional.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Subreddit {
+    #[serde(skip)]
+    pub name: String,
+
+    /// The listings to fetch posts from.
+    #[serde(deserialize_with = "listings")]
+    pub listings: Vec<PostType>,
+
+    /// The lowest score of posts to fetch.
+    pub min_score: i64,
+
+    /// Whether to mark images as verified as soon as they are fetched.
+    pub auto_verify: bool,
+
+    /// The flairs of posts to fetch, or empty for any.
+    pub flairs: Vec<String>,
+
+    /// The content types of images to store, or empty for any.
+    pub content_types: Vec<String>,
+
+    /// How long to wait between fetching posts.
+    #[serde(deserialize_with = "seconds")]
+    pub interval: Duration,
+}
+
+impl Subreddit {
+    /// The listings fetched unless configured otherwise.
+    pub const DEFAULT_LISTINGS: &'static [PostType] = &[PostType::Hot, PostType::New];
+
+    /// Check whether posts like this should be fetched from the subreddit.
+    pub fn accepts(&self, post: &RedditPost) -> bool {
+        post.score >= self.min_score
+            && (self.flairs.is_empty()
+                || post
+                    .link_flair_text
+                    .as_deref()
+                    .is_some_and(|flair| self.flairs.iter().any(|f| f == flair.trim())))
+    }
+
+    /// Check whether images of the content type should be stored.
+    pub fn accepts_content_type(&self, content_type: &str) -> bool {
+        self.content_types.is_empty() || self.content_types.iter().any(|t| t == content_type)
+    }
+}
+
+impl Default for Subreddit {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            listings: Self::DEFAULT_LISTINGS.to_vec(),
+            min_score: 1,
+            auto_verify: false,
+            flairs: vec![],
+            content_types: vec![],
+            interval: Duration::from_secs(600),
+        }
+    }
+}
+
+impl FromStr for Subreddit {
+    type Err = strum::ParseError;
+
+    /// Parse a subreddit with its listings, e.g. `birbs:hot+top/week`.
+    ///
+    /// Without any listings given, the default ones are fetched.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, listings) = match s.find(':') {
+            Some(idx) => (
+                &s[..idx],
+                s[idx + 1..]
+                    .split('+')
+                    .map(|l| l.trim().parse())
+                    .collect::<Result<_, _>>()?,
+            ),
+            None => (s, Self::DEFAULT_LISTINGS.to_vec()),
+        };
+        Ok(Self {
+            name: name.trim().to_owned(),
+            listings,
+            ..Default::default()
+        })
+    }
+}
+
+fn listings<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<PostType>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|listing| {
+            listing
+                .parse()
+                .map_err(|_| serde::de::Error::custom(format!("invalid listing `{}`", listing)))
+        })
+        .collect()
+}
+
+fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+impl Config {
+    /// Load the configuration.
+    ///
+    /// This is read from the TOML file at `CONFIG_FILE`, or `birbfetcher.toml`
+    /// by default. If there is no such file and none was explicitly set, the
+    /// subreddits are read from the `SUBREDDITS` env var instead, e.g.
+    /// `birbs,parrots:hot+top/week`.
+    pub fn load() -> Result<Self> {
+        let file = env::var("CONFIG_FILE");
+        let path = file.as_deref().unwrap_or(CONFIG_FILE);
+        if file.is_err() && !Path::new(path).exists() {
+            return Self::from_env();
+        }
+
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct File {
+            #[serde(default)]
+            subreddits: BTreeMap<String, Subreddit>,
+
+            #[serde(default)]
+            jobs: BTreeMap<String, Schedule>,
+        }
+
+        let file = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read config file `{}`", path))?;
+        let file: File =
+            toml::from_str(&file).with_context(|| format!("invalid config file `{}`", path))?;
+        Ok(Self {
+            subreddits: file
+                .subreddits
+                .into_iter()
+                .map(|(name, sub)| Subreddit { name, ..sub })
+                .collect(),
+            jobs: file.jobs,
+        })
+    }
+
+    fn from_env() -> Result<Self> {
+        let subreddits = env::var("SUBREDDITS")
+            .unwrap_or_else(|_| "birbs,parrots,birb".into())
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .context("`SUBREDDITS` must list subreddits with valid listings")?;
+        Ok(Self {
+            subreddits,
+            jobs: BTreeMap::new(),
+        })
+    }
+}
+
+/// Get the configuration currently in use.
+pub fn current() -> Arc<Config> {
+    CONFIG.read().unwrap().clone()
+}
+
+/// Load the configuration again, replacing the current one if it is valid.
+pub fn reload() -> Result<()> {
+    let config = Config::load()?;
+    info!(
+        "Loaded configuration of {} subreddits.",
+        config.subreddits.len()
+    );
+    *CONFIG.write().unwrap() = Arc::new(config);
+    Ok(())
+}
+
+/// Reload the configuration whenever we receive a SIGHUP.
+pub async fn reload_on_hangup() -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup()).context("could not listen for SIGHUP")?;
+    while hangups.recv().await.is_some() {
+        info!("Received SIGHUP, reloading configuration...");
+        if let Err(e) = reload() {
+            error!(
+                "Could not reload configuration, keeping the old one: {:?}",
+                e
+            );
+        }
+    }
+    Ok(())
+}
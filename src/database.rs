@@ -0,0 +1,1148 @@
+// birbfetcher - Collect bird images with ease.
+// Copyright (C) 2020-2021 Mariell Hoversholm
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::prelude::*;
+use async_trait::async_trait;
+use serde::Deserialize;
+use strum_macros::Display;
+
+/// How many random IDs to try for a random image before letting the database
+/// pick one.
+const RANDOM_PROBES: usize = 8;
+
+/// The columns selected for an `Image`.
+macro_rules! image_columns {
+    () => {
+        "id, hash, permalink, content_type, banned, verified, \
+         subreddit, title, author, posted_at, score, fetched_at, gallery_index, \
+         duplicate_of, width, height, size, frames, animated, duration_ms"
+    };
+}
+
+/// Implement `Repository` for a backend.
+///
+/// Every backend shares the same queries, written in the portable dialect
+/// described by `Backend::sql`. Only the integer type used for IDs differs, as
+/// not every backend has unsigned integers.
+macro_rules! repository {
+    ($name:ident, $db:ty, $row:ty, $pool:ty, $backend:expr, $int:ty) => {
+        /// A repository backed by a database connection pool.
+        pub struct $name {
+            pool: $pool,
+        }
+
+        impl $name {
+            /// Connect to the database at the given URL.
+            pub async fn connect(url: &str) -> Result<Self, sqlx::Error> {
+                Ok(Self {
+                    pool: <$pool>::new(url).await?,
+                })
+            }
+
+            /// Map a row with the `image_columns!()` to an `Image`.
+            fn image(row: $row) -> Result<Image, sqlx::Error> {
+                use sqlx::Row as _;
+
+                Ok(Image {
+                    id: row.try_get::<$int, _>("id")? as u32,
+                    hash: row.try_get("hash")?,
+                    permalink: row.try_get("permalink")?,
+                    content_type: row.try_get("content_type")?,
+                    banned: row.try_get("banned")?,
+                    verified: row.try_get("verified")?,
+                    subreddit: row.try_get("subreddit")?,
+                    title: row.try_get("title")?,
+                    author: row.try_get("author")?,
+                    posted_at: row.try_get("posted_at")?,
+                    score: row.try_get("score")?,
+                    fetched_at: row.try_get("fetched_at")?,
+                    gallery_index: row
+                        .try_get::<Option<i32>, _>("gallery_index")?
+                        .map(|i| i as u32),
+                    duplicate_of: row
+                        .try_get::<Option<$int>, _>("duplicate_of")?
+                        .map(|id| id as u32),
+                    media: MediaInfo {
+                        width: row.try_get::<Option<i32>, _>("width")?.map(|w| w as u32),
+                        height: row.try_get::<Option<i32>, _>("height")?.map(|h| h as u32),
+                        size: row.try_get::<Option<i64>, _>("size")?.map(|s| s as u64),
+                        frames: row.try_get::<Option<i32>, _>("frames")?.map(|f| f as u32),
+                        animated: row.try_get("animated")?,
+                        duration_ms: row
+                            .try_get::<Option<i64>, _>("duration_ms")?
+                            .map(|d| d as u64),
+                    },
+                })
+            }
+
+            /// Get the lowest or highest ID in use, if there are any images.
+            async fn edge_id(&self, order: SortOrder) -> Result<Option<u32>, sqlx::Error> {
+                let sql = format!("SELECT id FROM birbs ORDER BY id {} LIMIT 1", order);
+                let row: Option<($int,)> = sqlx::query_as(&sql).fetch_optional(&self.pool).await?;
+                Ok(row.map(|(id,)| id as u32))
+            }
+
+            /// Bind dynamically built parameters to a query, in order.
+            fn bind<'q>(
+                mut query: sqlx::Query<'q, $db>,
+                params: Vec<Param>,
+            ) -> sqlx::Query<'q, $db> {
+                for param in params {
+                    query = match param {
+                        Param::Bool(b) => query.bind(b),
+                        Param::Id(id) => query.bind(id as $int),
+                        Param::Int(i) => query.bind(i),
+                        Param::Text(s) => query.bind(s),
+                    };
+                }
+                query
+            }
+        }
+
+        #[async_trait]
+        impl Repository for $name {
+            async fn migrate(&self) -> Result<(), sqlx::Error> {
+                use crate::migrations::Migrations;
+                use strum::IntoEnumIterator as _;
+
+                // Make sure the table exists before we try to modify and fetch from it.
+                for query in Migrations::bootstrap($backend) {
+                    sqlx::query(&query).execute(&self.pool).await?;
+                }
+
+                // Fetch the version; no limit because there may only be 1 row.
+                let (version,): ($int,) = sqlx::query_as("SELECT version FROM meta_version")
+                    .fetch_one(&self.pool)
+                    .await?;
+                let version = version as u32;
+
+                debug!("Found DB version: {}", version);
+
+                // Let's use a transaction to be able to rollback.
+                let mut tx = self.pool.begin().await?;
+                for migration in Migrations::iter()
+                    // We only want to migrate if the version is currently below that which we want to
+                    // migrate up to. I.e. if we're version 1, we don't want to migrate to version 1.
+                    .filter(|mig| (*mig as u32) > version)
+                {
+                    // Migrations is #[repr(u32)].
+                    let ver = migration as u32;
+                    debug!("Applying migration to V{}", ver);
+
+                    for query in migration.queries($backend) {
+                        // If this query fails, the transaction is rolled back.
+                        sqlx::query(&query).execute(&mut tx).await?;
+                    }
+
+                    // If this query fails, the transaction is rolled back.
+                    sqlx::query(&format!("UPDATE meta_version SET version = {}", ver))
+                        .execute(&mut tx)
+                        .await?;
+                    debug!("Migration for V{} successful.", ver);
+                }
+                debug!("Committing transaction...");
+                tx.commit().await?;
+                debug!("Migrations finished!");
+
+                Ok(())
+            }
+
+            async fn random_image(
+                &self,
+                filter: &ImageFilter,
+            ) -> Result<Option<Image>, sqlx::Error> {
+                use rand::Rng as _;
+
+                // Rather than `ORDER BY RANDOM()`, which has to go through
+                // every row, pick random IDs until one is of a matching image.
+                // Each probe is a lookup in the primary key's index, and every
+                // matching image is equally likely to be picked.
+                let (low, high) = match (
+                    self.edge_id(SortOrder::Asc).await?,
+                    self.edge_id(SortOrder::Desc).await?,
+                ) {
+                    (Some(low), Some(high)) => (low, high),
+                    _ => return Ok(None),
+                };
+                let (conditions, params) = filter.conditions();
+                for _ in 0..RANDOM_PROBES {
+                    let id = rand::thread_rng().gen_range(u64::from(low), u64::from(high) + 1);
+                    let mut conditions = conditions.clone();
+                    let mut params = params.clone();
+                    conditions.push("id = ?".into());
+                    params.push(Param::Id(id as u32));
+
+                    let sql = $backend.sql(&format!(
+                        concat!("SELECT ", image_columns!(), " FROM birbs{} LIMIT 1"),
+                        where_clause(&conditions),
+                    ));
+                    let image = Self::bind(sqlx::query(&sql), params)
+                        .try_map(Self::image)
+                        .fetch_optional(&self.pool)
+                        .await?;
+                    if image.is_some() {
+                        return Ok(image);
+                    }
+                }
+
+                // Few images match the filter, or the IDs have large gaps. Take
+                // the first matching image from a random ID on instead, going
+                // around to the start if there is none after it. This walks the
+                // primary key's index rather than every row, but images after
+                // long runs of non-matching IDs are more likely to be picked.
+                let pivot = rand::thread_rng().gen_range(u64::from(low), u64::from(high) + 1) as u32;
+                let after = Page {
+                    cursor: pivot.checked_sub(1),
+                    limit: 1,
+                    order: SortOrder::Asc,
+                };
+                if let Some(image) = self.list_images(filter, &after).await?.pop() {
+                    return Ok(Some(image));
+                }
+                let first = Page {
+                    cursor: None,
+                    ..after
+                };
+                Ok(self.list_images(filter, &first).await?.pop())
+            }
+
+            async fn image_by_id(&self, id: u32) -> Result<Image, sqlx::Error> {
+                let sql = $backend.sql(concat!(
+                    "SELECT ",
+                    image_columns!(),
+                    " FROM birbs WHERE id = ? LIMIT 1",
+                ));
+                sqlx::query(&sql)
+                    .bind(id as $int)
+                    .try_map(Self::image)
+                    .fetch_one(&self.pool)
+                    .await
+            }
+
+            async fn list_images(
+                &self,
+                filter: &ImageFilter,
+                page: &Page,
+            ) -> Result<Vec<Image>, sqlx::Error> {
+                let (mut conditions, mut params) = filter.conditions();
+                if let Some(cursor) = page.cursor {
+                    conditions.push(match page.order {
+                        SortOrder::Asc => "id > ?".into(),
+                        SortOrder::Desc => "id < ?".into(),
+                    });
+                    params.push(Param::Id(cursor));
+                }
+
+                let sql = $backend.sql(&format!(
+                    concat!(
+                        "SELECT ",
+                        image_columns!(),
+                        " FROM birbs{} ORDER BY id {} LIMIT {}"
+                    ),
+                    where_clause(&conditions),
+                    page.order,
+                    page.limit,
+                ));
+                Self::bind(sqlx::query(&sql), params)
+                    .try_map(Self::image)
+                    .fetch_all(&self.pool)
+                    .await
+            }
+
+            async fn insert_image(&self, image: NewImage<'_>) -> Result<u32, sqlx::Error> {
+                let sql = $backend.sql(
+                    r#"
+                    INSERT INTO birbs
+                        (hash, permalink, source_url, content_type,
+                         subreddit, title, author, posted_at, score, fetched_at,
+                         gallery_index, verified, phash, duplicate_of,
+                         width, height, size, frames, animated, duration_ms)
+                    VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
+                );
+                sqlx::query(&sql)
+                    .bind(image.hash)
+                    .bind(image.permalink)
+                    .bind(image.source_url)
+                    .bind(image.content_type)
+                    .bind(image.subreddit.to_lowercase())
+                    .bind(image.title)
+                    .bind(image.author)
+                    .bind(image.posted_at)
+                    .bind(image.score)
+                    .bind(image.fetched_at)
+                    .bind(image.gallery_index.map(|i| i as i32))
+                    .bind(image.verified)
+                    .bind(image.phash.map(|phash| phash as i64))
+                    .bind(image.duplicate_of.map(|id| id as $int))
+                    .bind(image.media.width.map(|w| w as i32))
+                    .bind(image.media.height.map(|h| h as i32))
+                    .bind(image.media.size.map(|s| s as i64))
+                    .bind(image.media.frames.map(|f| f as i32))
+                    .bind(image.media.animated)
+                    .bind(image.media.duration_ms.map(|d| d as i64))
+                    .execute(&self.pool)
+                    .await?;
+
+                // Not every backend can return the ID from the insert itself.
+                self.image_id_by_hash(image.hash)
+                    .await?
+                    .ok_or(sqlx::Error::RowNotFound)
+            }
+
+            async fn image_id_by_hash(&self, hash: &[u8]) -> Result<Option<u32>, sqlx::Error> {
+                let sql = $backend.sql("SELECT id FROM birbs WHERE hash = ?");
+                let row: Option<($int,)> = sqlx::query_as(&sql)
+                    .bind(hash)
+                    .fetch_optional(&self.pool)
+                    .await?;
+                Ok(row.map(|(id,)| id as u32))
+            }
+
+            async fn phashes(&self) -> Result<Vec<(u32, u64)>, sqlx::Error> {
+                let sql = $backend.sql("SELECT id, phash FROM birbs WHERE phash IS NOT NULL");
+                let rows: Vec<($int, i64)> = sqlx::query_as(&sql).fetch_all(&self.pool).await?;
+                Ok(rows
+                    .into_iter()
+                    .map(|(id, phash)| (id as u32, phash as u64))
+                    .collect())
+            }
+
+            async fn next_without_media_info(
+                &self,
+                after: u32,
+            ) -> Result<Option<Image>, sqlx::Error> {
+                // Every probed image has its size recorded, even if nothing
+                // else could be decoded.
+                let sql = $backend.sql(concat!(
+                    "SELECT ",
+                    image_columns!(),
+                    " FROM birbs WHERE size IS NULL AND id > ? ORDER BY id ASC LIMIT 1",
+                ));
+                sqlx::query(&sql)
+                    .bind(after as $int)
+                    .try_map(Self::image)
+                    .fetch_optional(&self.pool)
+                    .await
+            }
+
+            async fn set_media_info(&self, id: u32, media: &MediaInfo) -> Result<(), sqlx::Error> {
+                let sql = $backend.sql(
+                    r#"
+                    UPDATE birbs
+                    SET width = ?, height = ?, size = ?, frames = ?, animated = ?, duration_ms = ?
+                    WHERE id = ?"#,
+                );
+                sqlx::query(&sql)
+                    .bind(media.width.map(|w| w as i32))
+                    .bind(media.height.map(|h| h as i32))
+                    .bind(media.size.map(|s| s as i64))
+                    .bind(media.frames.map(|f| f as i32))
+                    .bind(media.animated)
+                    .bind(media.duration_ms.map(|d| d as i64))
+                    .bind(id as $int)
+                    .execute(&self.pool)
+                    .await?;
+                Ok(())
+            }
+
+            async fn set_banned(&self, id: u32) -> Result<(), sqlx::Error> {
+                let sql =
+                    $backend.sql("UPDATE birbs SET banned = true, verified = false WHERE id = ?");
+                sqlx::query(&sql)
+                    .bind(id as $int)
+                    .execute(&self.pool)
+                    .await?;
+                Ok(())
+            }
+
+            async fn set_verified(&self, id: u32) -> Result<(), sqlx::Error> {
+                let sql =
+                    $backend.sql("UPDATE birbs SET verified = true, banned = false WHERE id = ?");
+                sqlx::query(&sql)
+                    .bind(id as $int)
+                    .execute(&self.pool)
+                    .await?;
+                Ok(())
+            }
+
+            async fn next_unverified(
+                &self,
+                after: u32,
+            ) -> Result<Option<(u32, String)>, sqlx::Error> {
+                let sql = $backend.sql(
+                    r#"
+                    SELECT id, permalink
+                    FROM birbs
+                    WHERE banned = false
+                        AND verified = false
+                        AND id > ?
+                    ORDER BY id ASC
+                    LIMIT 1"#,
+                );
+                let row: Option<($int, String)> = sqlx::query_as(&sql)
+                    .bind(after as $int)
+                    .fetch_optional(&self.pool)
+                    .await?;
+                Ok(row.map(|(id, permalink)| (id as u32, permalink)))
+            }
+
+            async fn backfill(
+                &self,
+                subreddit: &str,
+                listing: &str,
+            ) -> Result<Option<Backfill>, sqlx::Error> {
+                let sql = $backend.sql(
+                    "SELECT next_page, finished FROM backfills WHERE subreddit = ? AND listing = ?",
+                );
+                let row: Option<(Option<String>, bool)> = sqlx::query_as(&sql)
+                    .bind(subreddit)
+                    .bind(listing)
+                    .fetch_optional(&self.pool)
+                    .await?;
+                Ok(row.map(|(next_page, finished)| Backfill {
+                    next_page,
+                    finished,
+                }))
+            }
+
+            async fn save_backfill(
+                &self,
+                subreddit: &str,
+                listing: &str,
+                backfill: &Backfill,
+            ) -> Result<(), sqlx::Error> {
+                // Upserts are written differently by every backend.
+                let sql = $backend.sql(
+                    r#"
+                    UPDATE backfills
+                    SET next_page = ?, finished = ?
+                    WHERE subreddit = ? AND listing = ?"#,
+                );
+                let updated = sqlx::query(&sql)
+                    .bind(backfill.next_page.clone())
+                    .bind(backfill.finished)
+                    .bind(subreddit)
+                    .bind(listing)
+                    .execute(&self.pool)
+                    .await?;
+                if updated > 0 {
+                    return Ok(());
+                }
+
+                let sql = $backend.sql(
+                    r#"
+                    INSERT INTO backfills (subreddit, listing, next_page, finished)
+                    VALUES (?, ?, ?, ?)"#,
+                );
+                sqlx::query(&sql)
+                    .bind(subreddit)
+                    .bind(listing)
+                    .bind(backfill.next_page.clone())
+                    .bind(backfill.finished)
+                    .execute(&self.pool)
+                    .await?;
+                Ok(())
+            }
+
+            async fn job_state(&self, name: &str) -> Result<Option<JobState>, sqlx::Error> {
+                let sql = $backend.sql(
+                    r#"
+                    SELECT last_started_at, last_finished_at, next_run_at, last_error
+                    FROM jobs
+                    WHERE name = ?"#,
+                );
+                let row: Option<(Option<i64>, Option<i64>, Option<i64>, Option<String>)> =
+                    sqlx::query_as(&sql)
+                        .bind(name)
+                        .fetch_optional(&self.pool)
+                        .await?;
+                Ok(row.map(
+                    |(last_started_at, last_finished_at, next_run_at, last_error)| JobState {
+                        last_started_at,
+                        last_finished_at,
+                        next_run_at,
+                        last_error,
+                    },
+                ))
+            }
+
+            async fn save_job_state(&self, name: &str, state: &JobState) -> Result<(), sqlx::Error> {
+                // Upserts are written differently by every backend.
+                let sql = $backend.sql(
+                    r#"
+                    UPDATE jobs
+                    SET last_started_at = ?, last_finished_at = ?, next_run_at = ?, last_error = ?
+                    WHERE name = ?"#,
+                );
+                let updated = sqlx::query(&sql)
+                    .bind(state.last_started_at)
+                    .bind(state.last_finished_at)
+                    .bind(state.next_run_at)
+                    .bind(state.last_error.clone())
+                    .bind(name)
+                    .execute(&self.pool)
+                    .await?;
+                if updated > 0 {
+                    return Ok(());
+                }
+
+                let sql = $backend.sql(
+                    r#"
+                    INSERT INTO jobs (name, last_started_at, last_finished_at, next_run_at, last_error)
+                    VALUES (?, ?, ?, ?, ?)"#,
+                );
+                sqlx::query(&sql)
+                    .bind(name)
+                    .bind(state.last_started_at)
+                    .bind(state.last_finished_at)
+                    .bind(state.next_run_at)
+                    .bind(state.last_error.clone())
+                    .execute(&self.pool)
+                    .await?;
+                Ok(())
+            }
+        }
+    };
+}
+
+mod mysql;
+mod postgres;
+mod sqlite;
+
+pub use self::mysql::MySqlRepository;
+pub use self::postgres::PgRepository;
+pub use self::sqlite::SqliteRepository;
+
+/// The database backends we support.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Display)]
+pub enum Backend {
+    #[strum(serialize = "MySQL")]
+    MySql,
+
+    #[strum(serialize = "PostgreSQL")]
+    Postgres,
+
+    #[strum(serialize = "SQLite")]
+    Sqlite,
+}
+
+impl Backend {
+    /// Find the backend to use for a database URL by its scheme.
+    pub fn from_url(url: &str) -> Option<Self> {
+        let scheme = url.split(':').next()?;
+        match scheme {
+            "mysql" => Some(Self::MySql),
+            "postgres" | "postgresql" => Some(Self::Postgres),
+            "sqlite" => Some(Self::Sqlite),
+            _ => None,
+        }
+    }
+
+    /// Convert a query into the dialect of this backend.
+    ///
+    /// Queries are written with `?` for parameters, and must not use any
+    /// backend-specific quoting.
+    pub fn sql(self, query: &str) -> String {
+        match self {
+            Self::MySql | Self::Sqlite => query.to_owned(),
+            Self::Postgres => {
+                // PostgreSQL numbers its parameters: `$1`, `$2`, ...
+                let mut sql = String::with_capacity(query.len() + 8);
+                let mut param = 0;
+                for c in query.chars() {
+                    if c == '?' {
+                        param += 1;
+                        sql.push_str(&format!("${}", param));
+                    } else {
+                        sql.push(c);
+                    }
+                }
+                sql
+            }
+        }
+    }
+}
+
+/// An image as stored in the database.
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub id: u32,
+    pub hash: Vec<u8>,
+    pub permalink: String,
+    pub content_type: String,
+    pub banned: bool,
+    pub verified: bool,
+
+    // Metadata of the post the image was fetched from. These are only
+    // missing for images fetched before they were recorded.
+    pub subreddit: Option<String>,
+    pub title: Option<String>,
+    pub author: Option<String>,
+    /// When the post was created, in seconds since the Unix epoch.
+    pub posted_at: Option<i64>,
+    /// The score of the post when the image was fetched.
+    pub score: Option<i64>,
+    /// When the image was fetched, in seconds since the Unix epoch.
+    pub fetched_at: Option<i64>,
+    /// The position of the image within the post's gallery, if it is one.
+    pub gallery_index: Option<u32>,
+    /// The image this was found to be a near-duplicate of, if any.
+    pub duplicate_of: Option<u32>,
+    pub media: MediaInfo,
+}
+
+/// What an image or video itself is like.
+///
+/// Everything is missing for images fetched before this was recorded, and
+/// anything which could not be decoded is missing for the rest.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MediaInfo {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// The size of the file, in bytes.
+    pub size: Option<u64>,
+    pub frames: Option<u32>,
+    pub animated: Option<bool>,
+    /// How long the video lasts, in milliseconds.
+    pub duration_ms: Option<u64>,
+}
+
+/// A dynamically bound query parameter.
+#[derive(Debug, Clone)]
+enum Param {
+    Bool(bool),
+    Id(u32),
+    Int(i64),
+    Text(String),
+}
+
+/// Create a `WHERE` clause with all conditions joined, if there are any.
+fn where_clause(conditions: &[String]) -> String {
+    if conditions.is_empty() {
+        String::new()
+    } else {
+        format!(" WHERE {}", conditions.join(" AND "))
+    }
+}
+
+/// Criteria for which images to select.
+///
+/// Every criterion left as `None` matches all images.
+#[derive(Debug, Clone, Default)]
+pub struct ImageFilter {
+    pub verified: Option<bool>,
+    pub banned: Option<bool>,
+    pub content_type: Option<String>,
+    pub subreddit: Option<String>,
+    pub animated: Option<bool>,
+    pub min_width: Option<u32>,
+    pub min_height: Option<u32>,
+    /// The largest size of the file, in bytes.
+    pub max_size: Option<u64>,
+    /// The longest duration of videos, in milliseconds.
+    pub max_duration_ms: Option<u64>,
+}
+
+impl ImageFilter {
+    /// Get the SQL conditions for this filter, with their parameters.
+    fn conditions(&self) -> (Vec<String>, Vec<Param>) {
+        let mut conditions = Vec::new();
+        let mut params = Vec::new();
+        if let Some(verified) = self.verified {
+            conditions.push("verified = ?".into());
+            params.push(Param::Bool(verified));
+        }
+        if let Some(banned) = self.banned {
+            conditions.push("banned = ?".into());
+            params.push(Param::Bool(banned));
+        }
+        if let Some(ref content_type) = self.content_type {
+            conditions.push("content_type = ?".into());
+            params.push(Param::Text(content_type.clone()));
+        }
+        if let Some(ref subreddit) = self.subreddit {
+            conditions.push("subreddit = ?".into());
+            params.push(Param::Text(subreddit.to_lowercase()));
+        }
+        if let Some(animated) = self.animated {
+            // Images which haven't been probed are assumed to be animated by
+            // their content type.
+            let types = &crate::utils::ANIMATED_CONTENT_TYPES;
+            conditions.push(format!(
+                "COALESCE(animated, content_type IN ({})) = ?",
+                vec!["?"; types.len()].join(", "),
+            ));
+            params.extend(types.iter().map(|&ty| Param::Text(ty.to_owned())));
+            params.push(Param::Bool(animated));
+        }
+        // Images without the info never match these, such that only videos
+        // have a duration to match.
+        if let Some(min_width) = self.min_width {
+            conditions.push("width >= ?".into());
+            params.push(Param::Int(min_width.into()));
+        }
+        if let Some(min_height) = self.min_height {
+            conditions.push("height >= ?".into());
+            params.push(Param::Int(min_height.into()));
+        }
+        if let Some(max_size) = self.max_size {
+            conditions.push("size <= ?".into());
+            params.push(Param::Int(max_size as i64));
+        }
+        if let Some(max_duration_ms) = self.max_duration_ms {
+            conditions.push("duration_ms <= ?".into());
+            params.push(Param::Int(max_duration_ms as i64));
+        }
+        (conditions, params)
+    }
+}
+
+/// The order to sort images by their IDs in.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default, Display, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    #[strum(serialize = "ASC")]
+    Asc,
+
+    #[strum(serialize = "DESC")]
+    Desc,
+}
+
+/// A page of images to select.
+#[derive(Debug, Clone)]
+pub struct Page {
+    /// Only select images after this ID in the sort order.
+    pub cursor: Option<u32>,
+
+    /// The maximum amount of images to select.
+    pub limit: u32,
+
+    /// The order to sort images by their IDs in.
+    pub order: SortOrder,
+}
+
+/// How far the backfill of a listing of a subreddit has come.
+#[derive(Debug, Clone, Default)]
+pub struct Backfill {
+    /// The cursor of the next page to fetch.
+    pub next_page: Option<String>,
+
+    /// Whether the end of the listing has been reached.
+    pub finished: bool,
+}
+
+/// When a scheduled job has run and will run next.
+///
+/// Every time is in seconds since the Unix epoch.
+#[derive(Debug, Clone, Default)]
+pub struct JobState {
+    pub last_started_at: Option<i64>,
+
+    /// When the last run finished, unless it is still running.
+    pub last_finished_at: Option<i64>,
+
+    pub next_run_at: Option<i64>,
+
+    /// Why the last run failed, if it did.
+    pub last_error: Option<String>,
+}
+
+/// An image to be inserted into the database.
+#[derive(Debug, Clone, Copy)]
+pub struct NewImage<'a> {
+    pub hash: &'a [u8],
+    pub permalink: &'a str,
+    pub source_url: &'a str,
+    pub content_type: &'a str,
+    /// This is stored lowercased, such that filters can use its index.
+    pub subreddit: &'a str,
+    pub title: &'a str,
+    pub author: &'a str,
+    pub posted_at: i64,
+    pub score: i64,
+    pub fetched_at: i64,
+    pub gallery_index: Option<u32>,
+    pub verified: bool,
+    pub phash: Option<u64>,
+    pub duplicate_of: Option<u32>,
+    pub media: MediaInfo,
+}
+
+/// Data access for images, independent of the database backend.
+///
+/// Queries for single rows fail with `sqlx::Error::RowNotFound` if there is no
+/// such row.
+#[async_trait]
+pub trait Repository: Send + Sync {
+    /// Bring the database schema up to date.
+    async fn migrate(&self) -> Result<(), sqlx::Error>;
+
+    /// Get a random image matching the filter, if there is any.
+    async fn random_image(&self, filter: &ImageFilter) -> Result<Option<Image>, sqlx::Error>;
+
+    /// Get an image by its ID, whether it is banned or not.
+    async fn image_by_id(&self, id: u32) -> Result<Image, sqlx::Error>;
+
+    /// List images matching the filter, one page at a time.
+    async fn list_images(
+        &self,
+        filter: &ImageFilter,
+        page: &Page,
+    ) -> Result<Vec<Image>, sqlx::Error>;
+
+    /// Insert a newly fetched image, returning its ID.
+    async fn insert_image(&self, image: NewImage<'_>) -> Result<u32, sqlx::Error>;
+
+    /// Get the ID of the image with the given SHA-256 hash, if there is one.
+    async fn image_id_by_hash(&self, hash: &[u8]) -> Result<Option<u32>, sqlx::Error>;
+
+    /// Get the ID and perceptual hash of every image which has one.
+    async fn phashes(&self) -> Result<Vec<(u32, u64)>, sqlx::Error>;
+
+    /// Get the first image after the given ID which hasn't been probed for its
+    /// media info.
+    async fn next_without_media_info(&self, after: u32) -> Result<Option<Image>, sqlx::Error>;
+
+    /// Save the media info of an image.
+    async fn set_media_info(&self, id: u32, media: &MediaInfo) -> Result<(), sqlx::Error>;
+
+    /// Mark an image as banned, never to be served again.
+    async fn set_banned(&self, id: u32) -> Result<(), sqlx::Error>;
+
+    /// Mark an image as verified to be fine to serve.
+    async fn set_verified(&self, id: u32) -> Result<(), sqlx::Error>;
+
+    /// Get the ID and permalink of the first unverified, unbanned image after
+    /// the given ID.
+    async fn next_unverified(&self, after: u32) -> Result<Option<(u32, String)>, sqlx::Error>;
+
+    /// Get how far the backfill of a listing of a subreddit has come, if it
+    /// has started.
+    async fn backfill(
+        &self,
+        subreddit: &str,
+        listing: &str,
+    ) -> Result<Option<Backfill>, sqlx::Error>;
+
+    /// Save how far the backfill of a listing of a subreddit has come.
+    async fn save_backfill(
+        &self,
+        subreddit: &str,
+        listing: &str,
+        backfill: &Backfill,
+    ) -> Result<(), sqlx::Error>;
+
+    /// Get the state of a scheduled job, if it has ever been scheduled.
+    async fn job_state(&self, name: &str) -> Result<Option<JobState>, sqlx::Error>;
+
+    /// Save the state of a scheduled job.
+    async fn save_job_state(&self, name: &str, state: &JobState) -> Result<(), sqlx::Error>;
+}
+
+/// Connect to the database at the given URL, picking the backend by the URL's
+/// scheme.
+pub async fn connect(url: &str) -> anyhow::Result<Arc<dyn Repository>> {
+    let backend = Backend::from_url(url)
+        .ok_or_else(|| anyhow::anyhow!("unsupported database URL: {}", url))?;
+    info!("Connecting to {} database...", backend);
+    Ok(match backend {
+        Backend::MySql => Arc::new(MySqlRepository::connect(url).await?),
+        Backend::Postgres => Arc::new(PgRepository::connect(url).await?),
+        Backend::Sqlite => Arc::new(SqliteRepository::connect(url).await?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create a migrated repository of a database in memory.
+    async fn repository() -> SqliteRepository {
+        let repo = SqliteRepository::in_memory().await.unwrap();
+        repo.migrate().await.unwrap();
+        repo
+    }
+
+    fn new_image(hash: &[u8]) -> NewImage<'_> {
+        NewImage {
+            hash,
+            permalink: "/r/birbs/comments/abc/birb/",
+            source_url: "https://i.redd.it/birb.png",
+            content_type: "image/png",
+            subreddit: "birbs",
+            title: "A birb",
+            author: "birbwatcher",
+            posted_at: 1_600_000_000,
+            score: 42,
+            fetched_at: 1_600_000_100,
+            gallery_index: None,
+            verified: true,
+            phash: None,
+            duplicate_of: None,
+            media: MediaInfo::default(),
+        }
+    }
+
+    /// Insert images with the hashes `[0]`, `[1]`, ..., returning their IDs.
+    async fn insert_images(repo: &SqliteRepository, count: u8) -> Vec<u32> {
+        let mut ids = Vec::new();
+        for i in 0..count {
+            ids.push(repo.insert_image(new_image(&[i])).await.unwrap());
+        }
+        ids
+    }
+
+    /// A page with every image of the tests on it.
+    const ALL: Page = Page {
+        cursor: None,
+        limit: 100,
+        order: SortOrder::Asc,
+    };
+
+    fn ids(images: &[Image]) -> Vec<u32> {
+        images.iter().map(|image| image.id).collect()
+    }
+
+    #[test]
+    fn sql_numbers_parameters_for_postgres() {
+        let query = "SELECT id FROM birbs WHERE hash = ? AND id > ? LIMIT 1";
+        assert_eq!(Backend::MySql.sql(query), query);
+        assert_eq!(Backend::Sqlite.sql(query), query);
+        assert_eq!(
+            Backend::Postgres.sql(query),
+            "SELECT id FROM birbs WHERE hash = $1 AND id > $2 LIMIT 1"
+        );
+    }
+
+    #[tokio::test]
+    async fn migrating_again_does_nothing() {
+        let repo = repository().await;
+        let id = repo.insert_image(new_image(b"birb")).await.unwrap();
+
+        repo.migrate().await.unwrap();
+        let images = repo.list_images(&ImageFilter::default(), &ALL).await;
+        assert_eq!(ids(&images.unwrap()), vec![id]);
+    }
+
+    #[tokio::test]
+    async fn inserted_image_is_read_back() {
+        let repo = repository().await;
+        let media = MediaInfo {
+            width: Some(640),
+            height: Some(480),
+            size: Some(12_345),
+            frames: Some(1),
+            animated: Some(false),
+            duration_ms: None,
+        };
+        let id = repo
+            .insert_image(NewImage {
+                gallery_index: Some(2),
+                phash: Some(u64::MAX),
+                media,
+                ..new_image(b"birb")
+            })
+            .await
+            .unwrap();
+        assert_eq!(repo.image_id_by_hash(b"birb").await.unwrap(), Some(id));
+
+        let image = repo.image_by_id(id).await.unwrap();
+        assert_eq!(image.hash, b"birb");
+        assert_eq!(image.permalink, "/r/birbs/comments/abc/birb/");
+        assert_eq!(image.content_type, "image/png");
+        assert!(image.verified);
+        assert!(!image.banned);
+        assert_eq!(image.subreddit.as_deref(), Some("birbs"));
+        assert_eq!(image.title.as_deref(), Some("A birb"));
+        assert_eq!(image.author.as_deref(), Some("birbwatcher"));
+        assert_eq!(image.posted_at, Some(1_600_000_000));
+        assert_eq!(image.score, Some(42));
+        assert_eq!(image.fetched_at, Some(1_600_000_100));
+        assert_eq!(image.gallery_index, Some(2));
+        assert_eq!(image.duplicate_of, None);
+        assert_eq!(image.media.width, Some(640));
+        assert_eq!(image.media.height, Some(480));
+        assert_eq!(image.media.size, Some(12_345));
+        assert_eq!(image.media.frames, Some(1));
+        assert_eq!(image.media.animated, Some(false));
+        assert_eq!(image.media.duration_ms, None);
+        assert_eq!(repo.phashes().await.unwrap(), vec![(id, u64::MAX)]);
+    }
+
+    #[tokio::test]
+    async fn missing_and_duplicate_images_are_errors() {
+        let repo = repository().await;
+        let id = repo.insert_image(new_image(b"birb")).await.unwrap();
+
+        assert!(repo.insert_image(new_image(b"birb")).await.is_err());
+        assert_eq!(repo.image_id_by_hash(b"no birb").await.unwrap(), None);
+        match repo.image_by_id(id + 1).await {
+            Err(sqlx::Error::RowNotFound) => (),
+            other => panic!("expected no image, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn images_are_listed_in_pages() {
+        let repo = repository().await;
+        let all = insert_images(&repo, 5).await;
+        let filter = ImageFilter::default();
+
+        let mut asc = Page {
+            cursor: None,
+            limit: 2,
+            order: SortOrder::Asc,
+        };
+        let mut pages = Vec::new();
+        loop {
+            let page = repo.list_images(&filter, &asc).await.unwrap();
+            match page.last() {
+                Some(last) => asc.cursor = Some(last.id),
+                None => break,
+            }
+            pages.push(ids(&page));
+        }
+        assert_eq!(
+            pages,
+            vec![all[0..2].to_vec(), all[2..4].to_vec(), all[4..5].to_vec()]
+        );
+
+        let desc = Page {
+            cursor: Some(all[3]),
+            limit: 2,
+            order: SortOrder::Desc,
+        };
+        let page = repo.list_images(&filter, &desc).await.unwrap();
+        assert_eq!(ids(&page), vec![all[2], all[1]]);
+    }
+
+    #[tokio::test]
+    async fn random_image_matches_the_filter() {
+        let repo = repository().await;
+        assert!(repo
+            .random_image(&ImageFilter::default())
+            .await
+            .unwrap()
+            .is_none());
+
+        let ids = insert_images(&repo, 50).await;
+        // Only one image matches, so most picks fall back from probing IDs.
+        repo.set_banned(ids[0]).await.unwrap();
+        let banned = ImageFilter {
+            banned: Some(true),
+            ..Default::default()
+        };
+        for _ in 0..20 {
+            let image = repo.random_image(&banned).await.unwrap().unwrap();
+            assert_eq!(image.id, ids[0]);
+        }
+
+        let nothing = ImageFilter {
+            subreddit: Some("cats".into()),
+            ..Default::default()
+        };
+        assert!(repo.random_image(&nothing).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn subreddits_match_in_any_case() {
+        let repo = repository().await;
+        let id = repo
+            .insert_image(NewImage {
+                subreddit: "BirbsCanDance",
+                ..new_image(b"birb")
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            repo.image_by_id(id).await.unwrap().subreddit.as_deref(),
+            Some("birbscandance")
+        );
+
+        let filter = ImageFilter {
+            subreddit: Some("BIRBSCANDANCE".into()),
+            ..Default::default()
+        };
+        let images = repo.list_images(&filter, &ALL).await;
+        assert_eq!(ids(&images.unwrap()), vec![id]);
+    }
+
+    #[tokio::test]
+    async fn animated_falls_back_to_the_content_type() {
+        let repo = repository().await;
+        let gif = repo
+            .insert_image(NewImage {
+                content_type: "image/gif",
+                ..new_image(b"gif")
+            })
+            .await
+            .unwrap();
+        let still_gif = repo
+            .insert_image(NewImage {
+                content_type: "image/gif",
+                media: MediaInfo {
+                    animated: Some(false),
+                    ..Default::default()
+                },
+                ..new_image(b"still gif")
+            })
+            .await
+            .unwrap();
+        let png = repo.insert_image(new_image(b"png")).await.unwrap();
+
+        let animated = |animated| ImageFilter {
+            animated: Some(animated),
+            ..Default::default()
+        };
+        let images = repo.list_images(&animated(true), &ALL).await;
+        assert_eq!(ids(&images.unwrap()), vec![gif]);
+        let images = repo.list_images(&animated(false), &ALL).await;
+        assert_eq!(ids(&images.unwrap()), vec![still_gif, png]);
+    }
+
+    #[tokio::test]
+    async fn backfills_and_job_states_are_upserted() {
+        let repo = repository().await;
+        assert!(repo.backfill("birbs", "new").await.unwrap().is_none());
+        assert!(repo.job_state("probe").await.unwrap().is_none());
+
+        for page in &["t3_a", "t3_b"] {
+            let backfill = Backfill {
+                next_page: Some((*page).to_owned()),
+                finished: false,
+            };
+            repo.save_backfill("birbs", "new", &backfill).await.unwrap();
+        }
+        let backfill = repo.backfill("birbs", "new").await.unwrap().unwrap();
+        assert_eq!(backfill.next_page.as_deref(), Some("t3_b"));
+        assert!(!backfill.finished);
+        assert!(repo.backfill("birbs", "top").await.unwrap().is_none());
+
+        for &at in &[100, 200] {
+            let state = JobState {
+                last_started_at: Some(at),
+                last_error: Some(format!("failed at {}", at)),
+                ..Default::default()
+            };
+            repo.save_job_state("probe", &state).await.unwrap();
+        }
+        let state = repo.job_state("probe").await.unwrap().unwrap();
+        assert_eq!(state.last_started_at, Some(200));
+        assert_eq!(state.last_finished_at, None);
+        assert_eq!(state.last_error.as_deref(), Some("failed at 200"));
+    }
+}
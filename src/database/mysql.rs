@@ -0,0 +1,34 @@
+// birbfetcher - Collect bird images with ease.
+// Copyright (C) 2020-2021 Mariell Hoversholm
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::{
+    where_clause, Backend, Backfill, Image, ImageFilter, JobState, MediaInfo, NewImage, Page,
+    Param, Repository, SortOrder, RANDOM_PROBES,
+};
+use crate::prelude::*;
+use async_trait::async_trait;
+use sqlx::prelude::MySqlQueryAs as _;
+use sqlx::MySqlPool;
+
+// MySQL has unsigned integers, so IDs may use their full range.
+repository!(
+    MySqlRepository,
+    sqlx::MySql,
+    sqlx::mysql::MySqlRow,
+    MySqlPool,
+    Backend::MySql,
+    u32
+);
@@ -0,0 +1,53 @@
+// birbfetcher - Collect bird images with ease.
+// Copyright (C) 2020-2021 Mariell Hoversholm
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::{
+    where_clause, Backend, Backfill, Image, ImageFilter, JobState, MediaInfo, NewImage, Page,
+    Param, Repository, SortOrder, RANDOM_PROBES,
+};
+use crate::prelude::*;
+use async_trait::async_trait;
+use sqlx::prelude::SqliteQueryAs as _;
+use sqlx::SqlitePool;
+
+// SQLite stores every integer as a 64-bit signed integer.
+repository!(
+    SqliteRepository,
+    sqlx::Sqlite,
+    sqlx::sqlite::SqliteRow,
+    SqlitePool,
+    Backend::Sqlite,
+    i64
+);
+
+#[cfg(test)]
+impl SqliteRepository {
+    /// Create a repository of a new database in memory, which is gone once the
+    /// repository is dropped.
+    pub async fn in_memory() -> Result<Self, sqlx::Error> {
+        // Every connection would open a database of its own, so keep exactly
+        // one around. The colons are escaped, as sqlx would strip the leading
+        // one and open a file called `memory:` instead.
+        let pool = SqlitePool::builder()
+            .max_size(1)
+            .min_size(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .build("sqlite:%3Amemory%3A")
+            .await?;
+        Ok(Self { pool })
+    }
+}
@@ -14,6 +14,7 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::database::ImageFilter;
 use crate::prelude::*;
 use once_cell::sync::Lazy;
 use serenity::framework::standard::{
@@ -75,9 +76,7 @@ impl EventHandler for Handler {
 
         match reaction.emoji {
             ReactionType::Custom { id, .. } if *id.as_u64() == REACTIONS.ban => {
-                let err = sqlx::query("UPDATE birbs SET banned = true AND verified = false WHERE id = ?")
-                    .bind(img)
-                    .execute(db);
+                let err = db.set_banned(img);
                 let err = futures::executor::block_on(err);
 
                 if let Err(e) = err {
@@ -89,16 +88,14 @@ impl EventHandler for Handler {
                     }
                 } else if let Err(e) = reaction
                     .channel_id
-                    .say(&ctx.http, &format!("Banned ID {}", img))
+                    .say(&ctx.http, format!("Banned ID {}", img))
                 {
                     warn!("Could not send message: {:?}", e);
                 }
             }
 
             ReactionType::Custom { id, .. } if *id.as_u64() == REACTIONS.verify => {
-                let err = sqlx::query("UPDATE birbs SET verified = true AND banned = false WHERE id = ?")
-                    .bind(img)
-                    .execute(db);
+                let err = db.set_verified(img);
                 let err = futures::executor::block_on(err);
 
                 if let Err(e) = err {
@@ -110,7 +107,7 @@ impl EventHandler for Handler {
                     }
                 } else if let Err(e) = reaction
                     .channel_id
-                    .say(&ctx.http, &format!("Verified ID {}", img))
+                    .say(&ctx.http, format!("Verified ID {}", img))
                 {
                     warn!("Could not send message: {:?}", e);
                 }
@@ -132,7 +129,7 @@ struct ReactionIds {
 pub struct DatabaseContainer;
 
 impl TypeMapKey for DatabaseContainer {
-    type Value = MySqlPool;
+    type Value = Arc<dyn Repository>;
 }
 
 pub struct ImagesContainer;
@@ -171,9 +168,7 @@ pub fn ban(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
         .get::<DatabaseContainer>()
         .expect("database must exist");
 
-    let err = sqlx::query("UPDATE birbs SET banned = true AND verified = false WHERE id = ?")
-        .bind(id)
-        .execute(db);
+    let err = db.set_banned(id);
     let err = futures::executor::block_on(err);
 
     if let Err(e) = err {
@@ -183,7 +178,7 @@ pub fn ban(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
         {
             warn!("Could not send message: {:?}", e);
         }
-    } else if let Err(e) = msg.channel_id.say(&ctx.http, &format!("Banned ID {}", id)) {
+    } else if let Err(e) = msg.channel_id.say(&ctx.http, format!("Banned ID {}", id)) {
         warn!("Could not send message: {:?}", e);
     }
 
@@ -198,9 +193,7 @@ pub fn verify(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult
         .get::<DatabaseContainer>()
         .expect("database must exist");
 
-    let err = sqlx::query("UPDATE birbs SET verified = true AND banned = false WHERE id = ?")
-        .bind(id)
-        .execute(db);
+    let err = db.set_verified(id);
     let err = futures::executor::block_on(err);
 
     if let Err(e) = err {
@@ -210,10 +203,7 @@ pub fn verify(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult
         {
             warn!("Could not send message: {:?}", e);
         }
-    } else if let Err(e) = msg
-        .channel_id
-        .say(&ctx.http, &format!("Verified ID {}", id))
-    {
+    } else if let Err(e) = msg.channel_id.say(&ctx.http, format!("Verified ID {}", id)) {
         warn!("Could not send message: {:?}", e);
     }
 
@@ -227,12 +217,12 @@ pub fn image(ctx: &mut Context, msg: &Message, _: Args) -> CommandResult {
         .get::<DatabaseContainer>()
         .expect("database must exist");
 
-    let res = sqlx::query_as(
-        "SELECT id FROM birbs WHERE banned = false AND verified = false ORDER BY RAND() LIMIT 1",
-    )
-    .fetch_one(db);
-    let res = futures::executor::block_on(res);
-    let (imgid,): (u32,) = match res {
+    let res = futures::executor::block_on(db.random_image(&ImageFilter {
+        verified: Some(false),
+        banned: Some(false),
+        ..Default::default()
+    }));
+    let imgid = match res {
         Err(e) => {
             if let Err(e) = msg
                 .channel_id
@@ -242,7 +232,16 @@ pub fn image(ctx: &mut Context, msg: &Message, _: Args) -> CommandResult {
             }
             return Ok(());
         }
-        Ok(id) => id,
+        Ok(None) => {
+            if let Err(e) = msg
+                .channel_id
+                .say(&ctx.http, "There are no images left to verify.")
+            {
+                warn!("Could not send message: {:?}", e);
+            }
+            return Ok(());
+        }
+        Ok(Some(image)) => image.id,
     };
 
     let id = match msg.channel_id.send_message(&ctx.http, |m| {
@@ -251,10 +250,7 @@ pub fn image(ctx: &mut Context, msg: &Message, _: Args) -> CommandResult {
             e.field("ID", format!("{}", imgid), true);
             e
         });
-        m.reactions(vec![
-            REACTIONS.verify_id.clone(),
-            REACTIONS.ban_id.clone(),
-        ]);
+        m.reactions(vec![REACTIONS.verify_id.clone(), REACTIONS.ban_id.clone()]);
 
         m
     }) {
@@ -265,9 +261,6 @@ pub fn image(ctx: &mut Context, msg: &Message, _: Args) -> CommandResult {
         Ok(id) => id,
     };
 
-    // We need a mutable borrow now.
-    drop(db);
-
     let map = data
         .get_mut::<ImagesContainer>()
         .expect("images map must exist");
@@ -0,0 +1,134 @@
+// birbfetcher - Collect bird images with ease.
+// Copyright (C) 2020-2021 Mariell Hoversholm
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::prelude::*;
+use bytes::{Bytes, BytesMut};
+use once_cell::sync::Lazy;
+use reqwest::{RequestBuilder, Response};
+use sha2::{Digest as _, Sha256};
+use std::env;
+use std::future::Future;
+use std::time::Duration;
+use tempfile::{NamedTempFile, TempPath};
+use tokio::io::AsyncWriteExt as _;
+
+/// The largest file to download in bytes, set by `MAX_DOWNLOAD_SIZE`.
+pub static MAX_SIZE: Lazy<u64> = Lazy::new(|| match env::var("MAX_DOWNLOAD_SIZE") {
+    // Panicking is fine because only the env var may be invalid in this case
+    Ok(size) => size
+        .parse()
+        .expect("`MAX_DOWNLOAD_SIZE` must be a number of bytes"),
+    Err(_) => 100 * 1024 * 1024,
+});
+
+/// How long a download may take in total, set by `DOWNLOAD_TIMEOUT` in
+/// seconds.
+pub static TIMEOUT: Lazy<Duration> = Lazy::new(|| match env::var("DOWNLOAD_TIMEOUT") {
+    Ok(secs) => Duration::from_secs(
+        secs.parse()
+            .expect("`DOWNLOAD_TIMEOUT` must be a number of seconds"),
+    ),
+    Err(_) => Duration::from_secs(60),
+});
+
+/// A file downloaded to a temporary path.
+#[derive(Debug)]
+pub struct Download {
+    /// The file is deleted once this is dropped, unless it has been moved
+    /// away.
+    pub path: TempPath,
+
+    /// The SHA-256 hash of the file.
+    pub hash: Vec<u8>,
+}
+
+/// Download the response to the request into the temporary file, hashing it
+/// on the way.
+///
+/// The download is aborted once it is larger than `MAX_DOWNLOAD_SIZE` or takes
+/// longer than `DOWNLOAD_TIMEOUT`, such that no response can take up memory,
+/// disk space or time without end.
+pub async fn download(req: RequestBuilder, file: NamedTempFile) -> Result<Download, DownloadError> {
+    within_timeout(async {
+        let mut resp = send(req).await?;
+        let (file, path) = file.into_parts();
+        let mut file = tokio::fs::File::from_std(file);
+        let mut sha = Sha256::new();
+        let mut size = 0;
+        while let Some(chunk) = next_chunk(&mut resp, &mut size).await? {
+            sha.update(&chunk);
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+
+        Ok(Download {
+            path,
+            hash: sha.finalize().to_vec(),
+        })
+    })
+    .await
+}
+
+/// Fetch the entire response to the request into memory, within the same
+/// limits as `download`.
+///
+/// This is meant for small responses, such as those of APIs.
+pub async fn fetch(req: RequestBuilder) -> Result<Bytes, DownloadError> {
+    within_timeout(async {
+        let mut resp = send(req).await?;
+        let mut data = BytesMut::new();
+        let mut size = 0;
+        while let Some(chunk) = next_chunk(&mut resp, &mut size).await? {
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data.freeze())
+    })
+    .await
+}
+
+async fn within_timeout<T>(
+    download: impl Future<Output = Result<T, DownloadError>>,
+) -> Result<T, DownloadError> {
+    tokio::time::timeout(*TIMEOUT, download)
+        .await
+        .map_err(|_| DownloadError::Timeout(*TIMEOUT))?
+}
+
+/// Send the request, failing unless it was successful.
+async fn send(req: RequestBuilder) -> Result<Response, DownloadError> {
+    let resp = req.send().await?;
+    if !resp.status().is_success() {
+        return Err(DownloadError::Unsuccessful(resp.status()));
+    }
+    // No need to download what we know to be too large already.
+    if resp.content_length().is_some_and(|len| len > *MAX_SIZE) {
+        return Err(DownloadError::TooLarge(*MAX_SIZE));
+    }
+    Ok(resp)
+}
+
+/// Get the next chunk of the response, failing once all chunks so far add up
+/// to more than `MAX_DOWNLOAD_SIZE`.
+async fn next_chunk(resp: &mut Response, size: &mut u64) -> Result<Option<Bytes>, DownloadError> {
+    let chunk = resp.chunk().await?;
+    if let Some(ref chunk) = chunk {
+        *size += chunk.len() as u64;
+        if *size > *MAX_SIZE {
+            return Err(DownloadError::TooLarge(*MAX_SIZE));
+        }
+    }
+    Ok(chunk)
+}
@@ -34,6 +34,99 @@ pub enum RedditError {
 
     #[error("no such post was found")]
     NoPost,
+
+    /// We could not get an access token.
+    #[error("could not authenticate: {0}")]
+    Auth(String),
+}
+
+/// An error related to resolving links to external hosts.
+#[derive(Debug, Error)]
+pub enum ResolveError {
+    /// The host could not be requested correctly.
+    #[error("error when fetching result: {0}")]
+    Download(#[from] DownloadError),
+
+    /// Serde could not deserialise the result.
+    #[error("error when deserializing result: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+/// An error related to downloading files and other responses.
+#[derive(Debug, Error)]
+pub enum DownloadError {
+    /// The response could not be fetched correctly.
+    #[error("error when fetching response: {0}")]
+    Reqwest(#[from] reqwest::Error),
+
+    /// The HTTP request returned a bad status code.
+    #[error("unsuccessful http request: {0}")]
+    Unsuccessful(reqwest::StatusCode),
+
+    /// The response is larger than the given amount of bytes we download at
+    /// most.
+    #[error("response is larger than {0} bytes")]
+    TooLarge(u64),
+
+    /// The response could not be downloaded within the given time.
+    #[error("download timed out after {0:?}")]
+    Timeout(std::time::Duration),
+
+    /// The response could not be written to its temporary file.
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// An error related to storing and retrieving images.
+#[derive(Debug, Error)]
+pub enum StorageError {
+    /// The object could not be read or written on disk.
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// The object store returned an error.
+    #[error("s3 error: {0}")]
+    S3(String),
+
+    /// There is no object with the given key.
+    #[error("no such object")]
+    NotFound,
+}
+
+/// An error related to generating resized variants of images.
+#[derive(Debug, Error)]
+pub enum ThumbnailError {
+    /// The original could not be read, or the variant could not be stored.
+    #[error("storage error: {0}")]
+    Storage(#[from] StorageError),
+
+    /// The image could not be decoded or encoded.
+    #[error("image error: {0}")]
+    Image(#[from] image::ImageError),
+
+    /// The task resizing the image did not finish.
+    #[error("resizing task failed: {0}")]
+    Join(String),
+}
+
+/// An error related to stripping images of their metadata.
+#[derive(Debug, Error)]
+pub enum StripError {
+    /// The structure of the image could not be made sense of.
+    #[error("malformed image")]
+    Malformed,
+
+    /// The image could not be decoded or encoded to rotate it.
+    #[error("image error: {0}")]
+    Image(#[from] image::ImageError),
+
+    /// The task stripping the image did not finish.
+    #[error("stripping task failed: {0}")]
+    Join(String),
+
+    /// The image could not be read from or written to its file.
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
 }
 
 #[derive(Debug, Error)]
@@ -45,48 +138,166 @@ pub enum CheckingError {
     Reddit(#[from] RedditError),
 }
 
+/// An error related to backfilling the history of a subreddit.
+#[derive(Debug, Error)]
+pub enum BackfillError {
+    #[error("error when saving progress: {0}")]
+    Sql(#[from] sqlx::Error),
+
+    #[error("unsuccessful reddit api request: {0}")]
+    Reddit(#[from] RedditError),
+}
+
+/// An error related to adding the audio track to a Reddit video.
+#[derive(Debug, Error)]
+pub enum VideoError {
+    /// The audio track could not be fetched.
+    #[error("error when fetching audio: {0}")]
+    Download(#[from] DownloadError),
+
+    /// The video is larger than the given amount of bytes we store at most
+    /// once its audio is added.
+    #[error("video with audio is larger than {0} bytes")]
+    TooLarge(u64),
+
+    /// The tracks could not be written to or read from temporary files, or
+    /// ffmpeg could not be run.
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// ffmpeg failed to mux the tracks or probe the video.
+    #[error("ffmpeg failed: {0}")]
+    Ffmpeg(String),
+
+    /// The output of ffprobe could not be understood.
+    #[error("invalid ffprobe output: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+/// An error related to running scheduled jobs.
+#[derive(Debug, Error)]
+pub enum JobError {
+    #[error("sql error: {0}")]
+    Sql(#[from] sqlx::Error),
+
+    /// The job panicked with the given message.
+    #[error("job panicked: {0}")]
+    Panicked(String),
+}
+
+/// An error related to starting jobs.
+#[derive(Debug, Error)]
+pub enum SchedulerError {
+    #[error("no such job: {0}")]
+    NoSuchJob(String),
+
+    /// The job is already running, and may only run once at a time.
+    #[error("job {0} is already running")]
+    Running(String),
+}
+
 /// An error related to processing of images.
 #[derive(Debug, Error)]
 pub enum ProcessingError {
-    /// The image of the post could not be fetched correctly.
-    #[error("error when fetching image: {0}")]
-    Reqwest(#[from] reqwest::Error),
+    /// The image of the post could not be downloaded.
+    #[error("error when downloading image: {0}")]
+    Download(#[source] DownloadError),
 
-    /// The HTTP request to the image returned a bad status code.
-    #[error("unsuccessful http request: {0}")]
-    Unsuccessful(reqwest::StatusCode),
+    /// The image is larger than the given amount of bytes we download at most.
+    #[error("image is larger than {0} bytes")]
+    TooLarge(u64),
+
+    /// The image could not be downloaded within the given time.
+    #[error("download timed out after {0:?}")]
+    Timeout(std::time::Duration),
+
+    /// The image could not be read from or written to its temporary file.
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
 
     /// A disallowed content type was returned by the image's host.
     #[error("invalid content type")]
     InvalidContentType,
 
+    /// The file is not in any format we support, whatever its host labels it
+    /// as.
+    #[error("unsupported file format")]
+    UnsupportedFormat,
+
+    /// The link of the post could not be resolved into its media.
+    #[error("could not resolve link: {0}")]
+    Resolve(#[from] ResolveError),
+
+    /// The image could not be stripped of its metadata.
+    #[error("could not strip image: {0}")]
+    Strip(#[from] StripError),
+
     /// The post already exists in our database.
     #[error("post is a duplicate")]
     Duplicate,
 
+    /// The post is too similar to an image in our database.
+    #[error("post is a near-duplicate of image {of}")]
+    NearDuplicate { of: u32 },
+
     /// The post could not be saved.
     #[error("saving the image encountered an error: {0}")]
-    SaveError(#[from] std::io::Error),
+    SaveError(#[from] StorageError),
 
     /// The post could not be put into our database.
     #[error("sql error: {0}")]
     SqlError(#[from] sqlx::Error),
 }
 
+impl From<DownloadError> for ProcessingError {
+    fn from(e: DownloadError) -> Self {
+        // Hitting our own limits is about the image, rather than its host.
+        match e {
+            DownloadError::TooLarge(max) => Self::TooLarge(max),
+            DownloadError::Timeout(after) => Self::Timeout(after),
+            e => Self::Download(e),
+        }
+    }
+}
+
 /// An error related to the serving of images and information.
 #[derive(Debug, Error)]
 pub enum HttpErrorKind {
+    /// There is no image matching the request.
+    #[error("no such image")]
+    NotFound,
+
+    /// The admin API was requested without the right token.
+    #[error("invalid admin token")]
+    Unauthorized,
+
+    /// There is no run of a job matching the request.
+    #[error("no such run")]
+    NoSuchRun,
+
+    /// A job could not be started.
+    #[error("{0}")]
+    Scheduler(#[from] SchedulerError),
+
     /// An error occurred while fetching data from our database.
     #[error("sql error: {0}")]
-    SqlError(#[from] sqlx::Error),
+    Sql(#[from] sqlx::Error),
 
-    /// An error occurred while reading some file.
-    #[error("io error: {0}")]
-    IoError(#[from] std::io::Error),
+    /// An error occurred while reading from the image storage.
+    #[error("storage error: {0}")]
+    Storage(#[from] StorageError),
+
+    /// An error occurred while generating a resized variant of an image.
+    #[error("thumbnail error: {0}")]
+    Thumbnail(#[from] ThumbnailError),
+
+    /// A response could not be serialised to JSON.
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
 
     /// Warp returned an error in something HTTP related.
     #[error("warp http error: {0}")]
-    WarpHttpError(#[from] warp::http::Error),
+    WarpHttp(#[from] warp::http::Error),
 }
 
 /// An error wrapper with a status code for `HttpErrorKind`s.
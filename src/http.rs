@@ -14,14 +14,52 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::config::Subreddit;
+use crate::database::{Image, ImageFilter, Page, SortOrder};
+use crate::jobs::FetchNowJob;
 use crate::prelude::*;
+use crate::scheduler::{Outcome, Run, Scheduler};
+use crate::storage::{ByteStream, Storage};
+use crate::thumbnail::{self, Format};
 use anyhow::Result;
-use serde::Serialize;
+use chrono::{DateTime, TimeZone as _, Utc};
+use futures::{future, stream, StreamExt as _};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::Digest as _;
 use std::convert::Infallible;
-use std::fs;
-use std::path::PathBuf;
-use warp::http::{Response, StatusCode};
-use warp::{Rejection, Reply};
+use std::env;
+use warp::http::{header, Response, StatusCode};
+use warp::hyper::Body;
+use warp::{Filter, Rejection, Reply};
+
+/// The amount of images listed per page unless asked otherwise.
+const DEFAULT_LIST_LIMIT: u32 = 50;
+
+/// The maximum amount of images listed per page.
+const MAX_LIST_LIMIT: u32 = 100;
+
+/// The maximum amount of ranges served in one response.
+///
+/// Requests for more ranges are served the entire image instead.
+const MAX_RANGES: usize = 8;
+
+/// The `Cache-Control` of images by ID.
+///
+/// The image behind an ID never changes, but it may be banned, so this should
+/// not be cached forever.
+static CACHE_CONTROL_ID: Lazy<String> = Lazy::new(|| {
+    env::var("CACHE_CONTROL_ID").unwrap_or_else(|_| "public, max-age=86400, immutable".into())
+});
+
+/// The `Cache-Control` of image info by ID, which changes when the image is
+/// verified or banned.
+static CACHE_CONTROL_INFO: Lazy<String> =
+    Lazy::new(|| env::var("CACHE_CONTROL_INFO").unwrap_or_else(|_| "public, max-age=300".into()));
+
+/// The `Cache-Control` of random images and their info.
+static CACHE_CONTROL_RANDOM: Lazy<String> =
+    Lazy::new(|| env::var("CACHE_CONTROL_RANDOM").unwrap_or_else(|_| "no-store".into()));
 
 #[derive(Serialize)]
 struct ImageData {
@@ -31,6 +69,47 @@ struct ImageData {
     content_type: String,
     banned: bool,
     verified: bool,
+    subreddit: Option<String>,
+    title: Option<String>,
+    author: Option<String>,
+    posted_at: Option<i64>,
+    score: Option<i64>,
+    fetched_at: Option<i64>,
+    gallery_index: Option<u32>,
+    duplicate_of: Option<u32>,
+    width: Option<u32>,
+    height: Option<u32>,
+    size: Option<u64>,
+    frames: Option<u32>,
+    animated: Option<bool>,
+    duration_ms: Option<u64>,
+}
+
+impl From<Image> for ImageData {
+    fn from(image: Image) -> Self {
+        Self {
+            id: image.id,
+            hash: hex::encode_upper(image.hash),
+            permalink: image.permalink,
+            content_type: image.content_type,
+            banned: image.banned,
+            verified: image.verified,
+            subreddit: image.subreddit,
+            title: image.title,
+            author: image.author,
+            posted_at: image.posted_at,
+            score: image.score,
+            fetched_at: image.fetched_at,
+            gallery_index: image.gallery_index,
+            duplicate_of: image.duplicate_of,
+            width: image.media.width,
+            height: image.media.height,
+            size: image.media.size,
+            frames: image.media.frames,
+            animated: image.media.animated,
+            duration_ms: image.media.duration_ms,
+        }
+    }
 }
 
 // {{{ Macros
@@ -60,36 +139,263 @@ macro_rules! cookie {
 }
 // }}}
 
+// {{{ Conditional requests
+/// The headers of a conditional or range request.
+pub struct Conditional {
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+    range: Option<String>,
+    if_range: Option<String>,
+}
+
+impl Conditional {
+    /// Check whether the client already has the representation with the given
+    /// entity tag and modification time, and should be sent a
+    /// `304 Not Modified`.
+    ///
+    /// `If-Modified-Since` is only considered without `If-None-Match`.
+    fn not_modified(&self, etag: &str, last_modified: Option<DateTime<Utc>>) -> bool {
+        if let Some(ref tags) = self.if_none_match {
+            return tags
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+        }
+
+        let since = self
+            .if_modified_since
+            .as_deref()
+            .and_then(|since| DateTime::parse_from_rfc2822(since).ok());
+        match (since, last_modified) {
+            (Some(since), Some(modified)) => modified.timestamp() <= since.timestamp(),
+            _ => false,
+        }
+    }
+
+    /// Get the ranges requested of the representation with the given entity
+    /// tag, modification time and size.
+    ///
+    /// Returns `None` if the entire representation should be served, and no
+    /// ranges if none of them can be satisfied.
+    fn ranges(
+        &self,
+        etag: &str,
+        last_modified: Option<DateTime<Utc>>,
+        size: u64,
+    ) -> Option<Vec<ByteRange>> {
+        let range = self.range.as_deref()?;
+
+        // The ranges are only of use if the client has this representation.
+        if let Some(ref if_range) = self.if_range {
+            let fresh = if if_range.starts_with('"') {
+                if_range == etag
+            } else {
+                DateTime::parse_from_rfc2822(if_range)
+                    .ok()
+                    .zip(last_modified)
+                    .is_some_and(|(date, modified)| date.timestamp() == modified.timestamp())
+            };
+            if !fresh {
+                return None;
+            }
+        }
+
+        ByteRange::parse(range, size).filter(|ranges| ranges.len() <= MAX_RANGES)
+    }
+}
+
+/// Extract the conditional and range request headers.
+pub fn conditional() -> impl Filter<Extract = (Conditional,), Error = Rejection> + Clone {
+    warp::header::optional("if-none-match")
+        .and(warp::header::optional("if-modified-since"))
+        .and(warp::header::optional("range"))
+        .and(warp::header::optional("if-range"))
+        .map(
+            |if_none_match, if_modified_since, range, if_range| Conditional {
+                if_none_match,
+                if_modified_since,
+                range,
+                if_range,
+            },
+        )
+}
+
+/// A range of bytes, from `start` up to and including `end`.
+#[derive(Copy, Clone, Debug)]
+struct ByteRange {
+    start: u64,
+    end: u64,
+}
+
+impl ByteRange {
+    /// Parse a `Range` header for a representation of the given size.
+    ///
+    /// Returns `None` if the header is invalid and should be ignored, and only
+    /// the ranges which can be satisfied otherwise.
+    fn parse(header: &str, size: u64) -> Option<Vec<Self>> {
+        let specs = header.trim().strip_prefix("bytes=")?;
+        let mut ranges = Vec::new();
+        for spec in specs.split(',') {
+            let (start, end) = spec.trim().split_once('-')?;
+            if start.is_empty() {
+                // A suffix range of the last bytes.
+                let suffix: u64 = end.parse().ok()?;
+                if suffix > 0 && size > 0 {
+                    ranges.push(Self {
+                        start: size.saturating_sub(suffix),
+                        end: size - 1,
+                    });
+                }
+                continue;
+            }
+
+            let start: u64 = start.parse().ok()?;
+            let end = match end {
+                "" => u64::MAX,
+                end => end.parse().ok()?,
+            };
+            if end < start {
+                return None;
+            }
+            if start < size {
+                ranges.push(Self {
+                    start,
+                    end: end.min(size - 1),
+                });
+            }
+        }
+
+        Some(ranges)
+    }
+
+    /// The amount of bytes in this range.
+    fn len(self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// The `Content-Range` of this range of a representation of the given
+    /// size.
+    fn content_range(self, size: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, size)
+    }
+}
+
+/// Format a time as an HTTP date.
+fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Reply with the value as JSON, tagged with the hash of the JSON.
+fn json_reply<T: Serialize>(
+    value: &T,
+    cache_control: &str,
+    conditional: &Conditional,
+) -> Result<Response<Body>, HttpError> {
+    let json = serde_json::to_vec(value).status(StatusCode::INTERNAL_SERVER_ERROR)?;
+    let etag = format!(
+        r#""{}""#,
+        hex::encode_upper(crate::utils::sha256(|h| h.update(&json)))
+    );
+
+    let response = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::CACHE_CONTROL, cache_control);
+    if conditional.not_modified(&etag, None) {
+        return response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .status(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    response
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(json))
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+}
+// }}}
+
 // {{{ GET /random/image - random image
-pub async fn random_image(db: &MySqlPool, birb_dir: &PathBuf) -> Result<impl Reply, Rejection> {
+/// The query parameters of the random endpoints, narrowing down which images
+/// may be picked.
+#[derive(Deserialize)]
+pub struct RandomQuery {
+    verified: Option<bool>,
+    subreddit: Option<String>,
+    #[serde(rename = "type")]
+    content_type: Option<String>,
+    animated: Option<bool>,
+    min_width: Option<u32>,
+    min_height: Option<u32>,
+    max_size: Option<u64>,
+    max_duration_ms: Option<u64>,
+}
+
+impl From<RandomQuery> for ImageFilter {
+    fn from(query: RandomQuery) -> Self {
+        Self {
+            verified: query.verified,
+            // Banned images are never served at random.
+            banned: Some(false),
+            content_type: query.content_type,
+            subreddit: query.subreddit,
+            animated: query.animated,
+            min_width: query.min_width,
+            min_height: query.min_height,
+            max_size: query.max_size,
+            max_duration_ms: query.max_duration_ms,
+        }
+    }
+}
+
+pub async fn random_image(
+    db: &dyn Repository,
+    storage: &dyn Storage,
+    query: RandomQuery,
+    conditional: Conditional,
+) -> Result<impl Reply, Rejection> {
     delegate! {
-        random_image_impl(db, birb_dir) => |e|
+        random_image_impl(db, storage, query, conditional) => |e|
             error!("Error upon calling random image HTTP endpoint: {}", e)
     }
 }
 
-async fn random_image_impl(db: &MySqlPool, birb_dir: &PathBuf) -> Result<impl Reply, HttpError> {
-    serve_image(
-        birb_dir,
-
-        sqlx::query_as(
-            "SELECT id, hash, permalink, content_type FROM birbs WHERE banned = false ORDER BY RAND() LIMIT 1"
-        )
-        .fetch_one(db)
+async fn random_image_impl(
+    db: &dyn Repository,
+    storage: &dyn Storage,
+    query: RandomQuery,
+    conditional: Conditional,
+) -> Result<impl Reply, HttpError> {
+    let image = db
+        .random_image(&query.into())
         .await
         .status(StatusCode::INTERNAL_SERVER_ERROR)?
-    ).await
+        .ok_or(HttpErrorKind::NotFound)
+        .status(StatusCode::NOT_FOUND)?;
+
+    serve_image(storage, image, None, &CACHE_CONTROL_RANDOM, &conditional).await
 }
 // }}}
 
 // {{{ GET /id/:id - get image by id
+/// The query parameters for getting a resized variant of an image.
+#[derive(Deserialize)]
+pub struct ResizeQuery {
+    /// The width to scale the image down to, rounded up to one of the widths
+    /// variants are generated in.
+    w: Option<u32>,
+    #[serde(default)]
+    format: Format,
+}
+
 pub async fn get_by_id(
-    db: &MySqlPool,
-    birb_dir: &PathBuf,
+    db: &dyn Repository,
+    storage: &dyn Storage,
     id: u32,
+    query: ResizeQuery,
+    conditional: Conditional,
 ) -> Result<impl Reply, Rejection> {
     delegate! {
-        get_by_id_impl(db, birb_dir, id) => |e|
+        get_by_id_impl(db, storage, id, query, conditional) => |e|
             error!(
                 "Error upon calling get_by_id HTTP endpoint for ID {}: {}",
                 id, e
@@ -98,58 +404,99 @@ pub async fn get_by_id(
 }
 
 async fn get_by_id_impl(
-    db: &MySqlPool,
-    birb_dir: &PathBuf,
+    db: &dyn Repository,
+    storage: &dyn Storage,
     id: u32,
+    query: ResizeQuery,
+    conditional: Conditional,
 ) -> Result<impl Reply, HttpError> {
-    serve_image(
-        birb_dir,
+    let image = db.image_by_id(id).await.status(StatusCode::NOT_FOUND)?;
+    if image.banned {
+        return Err(HttpErrorKind::NotFound.status(StatusCode::NOT_FOUND));
+    }
 
-        sqlx::query_as(
-            "SELECT id, hash, permalink, content_type FROM birbs WHERE banned = false AND id = ? LIMIT 1"
-        )
-        .bind(id)
-        .fetch_one(db)
-        .await
-        .status(StatusCode::NOT_FOUND)?
-    ).await
+    let variant = query.w.map(|width| (thumbnail::width(width), query.format));
+    serve_image(storage, image, variant, &CACHE_CONTROL_ID, &conditional).await
+}
+// }}}
+
+// {{{ GET /thumb/:id - get thumbnail of image by id
+pub async fn get_thumbnail(
+    db: &dyn Repository,
+    storage: &dyn Storage,
+    id: u32,
+    query: ResizeQuery,
+    conditional: Conditional,
+) -> Result<impl Reply, Rejection> {
+    delegate! {
+        get_thumbnail_impl(db, storage, id, query, conditional) => |e|
+            error!(
+                "Error upon calling get_thumbnail HTTP endpoint for ID {}: {}",
+                id, e
+            )
+    }
+}
+
+async fn get_thumbnail_impl(
+    db: &dyn Repository,
+    storage: &dyn Storage,
+    id: u32,
+    query: ResizeQuery,
+    conditional: Conditional,
+) -> Result<impl Reply, HttpError> {
+    let image = db.image_by_id(id).await.status(StatusCode::NOT_FOUND)?;
+    if image.banned {
+        return Err(HttpErrorKind::NotFound.status(StatusCode::NOT_FOUND));
+    }
+
+    let width = thumbnail::width(query.w.unwrap_or(thumbnail::THUMBNAIL_WIDTH));
+    serve_image(
+        storage,
+        image,
+        Some((width, query.format)),
+        &CACHE_CONTROL_ID,
+        &conditional,
+    )
+    .await
 }
 // }}}
 
 // {{{ GET /info/random - get info of random image
-pub async fn get_random_info(db: &MySqlPool) -> Result<impl Reply, Rejection> {
+pub async fn get_random_info(
+    db: &dyn Repository,
+    query: RandomQuery,
+    conditional: Conditional,
+) -> Result<impl Reply, Rejection> {
     delegate! {
-        get_random_info_impl(db) => |e|
+        get_random_info_impl(db, query, conditional) => |e|
             error!("Error upon calling get_random_info HTTP endpoint: {}", e)
     }
 }
 
-async fn get_random_info_impl(db: &MySqlPool) -> Result<impl Reply, HttpError> {
-    let (id, hash, permalink, content_type, banned, verified): (u32, Vec<u8>, String, String, bool, bool) =
-        sqlx::query_as(
-            "SELECT id, hash, permalink, content_type, banned, verified FROM birbs WHERE banned = false ORDER BY RAND() LIMIT 1",
-        )
-        .fetch_one(db)
+async fn get_random_info_impl(
+    db: &dyn Repository,
+    query: RandomQuery,
+    conditional: Conditional,
+) -> Result<impl Reply, HttpError> {
+    let image = db
+        .random_image(&query.into())
         .await
+        .status(StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(HttpErrorKind::NotFound)
         .status(StatusCode::NOT_FOUND)?;
 
-    let data = ImageData {
-        id,
-        hash: hex::encode_upper(hash),
-        permalink,
-        content_type,
-        banned,
-        verified,
-    };
-
-    Ok(warp::reply::json(&data))
+    json_reply(&ImageData::from(image), &CACHE_CONTROL_RANDOM, &conditional)
 }
 // }}}
 
 // {{{ GET /info/id/:id - get image info by id
-pub async fn get_info_by_id(db: &MySqlPool, id: u32) -> Result<impl Reply, Rejection> {
+pub async fn get_info_by_id(
+    db: &dyn Repository,
+    id: u32,
+    conditional: Conditional,
+) -> Result<impl Reply, Rejection> {
     delegate! {
-        get_info_by_id_impl(db, id) => |e|
+        get_info_by_id_impl(db, id, conditional) => |e|
             error!(
                 "Error upon calling get_info_by_id HTTP endpoint for ID {}: {}",
                 id, e
@@ -157,57 +504,391 @@ pub async fn get_info_by_id(db: &MySqlPool, id: u32) -> Result<impl Reply, Rejec
     }
 }
 
-async fn get_info_by_id_impl(db: &MySqlPool, id: u32) -> Result<impl Reply, HttpError> {
-    let (id, hash, permalink, content_type, banned, verified): (u32, Vec<u8>, String, String, bool, bool) =
-        sqlx::query_as(
-            "SELECT id, hash, permalink, content_type, banned, verified FROM birbs WHERE id = ? LIMIT 1",
-        )
-        .bind(id)
-        .fetch_one(db)
+async fn get_info_by_id_impl(
+    db: &dyn Repository,
+    id: u32,
+    conditional: Conditional,
+) -> Result<impl Reply, HttpError> {
+    let image = db.image_by_id(id).await.status(StatusCode::NOT_FOUND)?;
+
+    json_reply(&ImageData::from(image), &CACHE_CONTROL_INFO, &conditional)
+}
+// }}}
+
+// {{{ GET /list - list image info
+/// The query parameters of `GET /list`.
+#[derive(Deserialize)]
+pub struct ListQuery {
+    cursor: Option<u32>,
+    limit: Option<u32>,
+    verified: Option<bool>,
+    banned: Option<bool>,
+    content_type: Option<String>,
+    subreddit: Option<String>,
+    animated: Option<bool>,
+    min_width: Option<u32>,
+    min_height: Option<u32>,
+    max_size: Option<u64>,
+    max_duration_ms: Option<u64>,
+    #[serde(default)]
+    order: SortOrder,
+}
+
+#[derive(Serialize)]
+struct ImageList {
+    images: Vec<ImageData>,
+
+    /// The cursor to get the next page with, if there is one.
+    next_cursor: Option<u32>,
+}
+
+pub async fn list_images(db: &dyn Repository, query: ListQuery) -> Result<impl Reply, Rejection> {
+    delegate! {
+        list_images_impl(db, query) => |e|
+            error!("Error upon calling list_images HTTP endpoint: {}", e)
+    }
+}
+
+async fn list_images_impl(db: &dyn Repository, query: ListQuery) -> Result<impl Reply, HttpError> {
+    let filter = ImageFilter {
+        verified: query.verified,
+        // Banned images are only listed when explicitly asked for.
+        banned: Some(query.banned.unwrap_or(false)),
+        content_type: query.content_type,
+        subreddit: query.subreddit,
+        animated: query.animated,
+        min_width: query.min_width,
+        min_height: query.min_height,
+        max_size: query.max_size,
+        max_duration_ms: query.max_duration_ms,
+    };
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .clamp(1, MAX_LIST_LIMIT);
+    let page = Page {
+        cursor: query.cursor,
+        // Fetch one more to know whether there is a next page.
+        limit: limit + 1,
+        order: query.order,
+    };
+
+    let mut images = db
+        .list_images(&filter, &page)
         .await
-        .status(StatusCode::NOT_FOUND)?;
+        .status(StatusCode::INTERNAL_SERVER_ERROR)?;
+    let next_cursor = if images.len() > limit as usize {
+        images.truncate(limit as usize);
+        images.last().map(|i| i.id)
+    } else {
+        None
+    };
 
-    let data = ImageData {
-        id,
-        hash: hex::encode_upper(hash),
-        permalink,
-        content_type,
-        banned,
-        verified,
+    Ok(warp::reply::json(&ImageList {
+        images: images.into_iter().map(ImageData::from).collect(),
+        next_cursor,
+    }))
+}
+// }}}
+
+// {{{ Admin API
+/// The token the admin API requires, set by `ADMIN_TOKEN`.
+///
+/// Every admin request is rejected unless this is set.
+static ADMIN_TOKEN: Lazy<Option<Vec<u8>>> = Lazy::new(|| {
+    env::var("ADMIN_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty())
+        .map(|token| crate::utils::sha256(|h| h.update(token.as_bytes())))
+});
+
+/// Require the admin token as a bearer token.
+pub fn admin() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional("authorization")
+        .and_then(|authorization: Option<String>| async move {
+            let token = authorization
+                .as_deref()
+                .and_then(|auth| auth.strip_prefix("Bearer "))
+                // Compare hashes, such that the time taken reveals nothing of the token.
+                .map(|token| crate::utils::sha256(|h| h.update(token.trim().as_bytes())));
+            match (&*ADMIN_TOKEN, token) {
+                (Some(expected), Some(token)) if *expected == token => Ok(()),
+                _ => Err(warp::reject::custom(
+                    HttpErrorKind::Unauthorized.status(StatusCode::UNAUTHORIZED),
+                )),
+            }
+        })
+        .untuple_one()
+}
+
+#[derive(Serialize)]
+struct RunData {
+    id: u64,
+    job: String,
+    /// Either `running`, `succeeded` or `failed`.
+    status: &'static str,
+    started_at: i64,
+    finished_at: Option<i64>,
+    /// How many steps of the run are done.
+    done: u64,
+    /// How many steps the run has, if known.
+    total: Option<u64>,
+    error: Option<String>,
+}
+
+impl From<&Run> for RunData {
+    fn from(run: &Run) -> Self {
+        let outcome = run.outcome();
+        Self {
+            id: run.id,
+            job: run.job.clone(),
+            status: match outcome {
+                None => "running",
+                Some(Outcome { error: None, .. }) => "succeeded",
+                Some(Outcome { error: Some(_), .. }) => "failed",
+            },
+            started_at: run.started_at,
+            finished_at: outcome.as_ref().map(|outcome| outcome.finished_at),
+            done: run.progress.done(),
+            total: run.progress.total(),
+            error: outcome.and_then(|outcome| outcome.error),
+        }
+    }
+}
+
+/// Reply that a run was started, with where to follow its progress.
+fn run_started(run: Result<Arc<Run>, SchedulerError>) -> Result<Response<Body>, HttpError> {
+    let run = run.map_err(|e| {
+        let status = match e {
+            SchedulerError::NoSuchJob(_) => StatusCode::NOT_FOUND,
+            SchedulerError::Running(_) => StatusCode::CONFLICT,
+        };
+        e.status(status)
+    })?;
+    let json =
+        serde_json::to_vec(&RunData::from(&*run)).status(StatusCode::INTERNAL_SERVER_ERROR)?;
+    Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .header(header::LOCATION, format!("/admin/jobs/runs/{}", run.id))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(json))
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+// {{{ POST /admin/jobs/:name - run a scheduled job now
+pub async fn trigger_job(
+    scheduler: &Arc<Scheduler>,
+    name: String,
+) -> Result<impl Reply, Rejection> {
+    delegate! {
+        async { run_started(scheduler.trigger(&name)) } => |e|
+            error!("Error upon calling trigger_job HTTP endpoint: {}", e)
+    }
+}
+// }}}
+
+// {{{ POST /admin/jobs/fetch(/:subreddit) - fetch posts now
+/// Fetch the posts of every subreddit, or only the given one, right away.
+///
+/// A subreddit which is not configured is fetched with the default settings.
+pub async fn fetch_now(
+    scheduler: &Arc<Scheduler>,
+    db: Arc<dyn Repository>,
+    storage: Arc<dyn Storage>,
+    pages: usize,
+    subreddit: Option<String>,
+) -> Result<impl Reply, Rejection> {
+    let config = crate::config::current();
+    let (name, subreddits) = match subreddit {
+        None => ("fetch".to_owned(), config.subreddits.clone()),
+        Some(name) => {
+            let sub = config
+                .subreddits
+                .iter()
+                .find(|sub| sub.name.eq_ignore_ascii_case(&name))
+                .cloned()
+                .unwrap_or_else(|| Subreddit {
+                    name,
+                    ..Default::default()
+                });
+            (format!("fetch/{}", sub.name), vec![sub])
+        }
+    };
+    let job = FetchNowJob {
+        db,
+        storage,
+        pages,
+        subreddits,
     };
 
-    Ok(warp::reply::json(&data))
+    delegate! {
+        async { run_started(scheduler.run_once(&name, job)) } => |e|
+            error!("Error upon calling fetch_now HTTP endpoint: {}", e)
+    }
 }
 // }}}
 
+// {{{ GET /admin/jobs/runs/:id - get progress of a run
+pub async fn get_run(scheduler: &Scheduler, id: u64) -> Result<impl Reply, Rejection> {
+    delegate! {
+        get_run_impl(scheduler, id) => |e|
+            error!("Error upon calling get_run HTTP endpoint: {}", e)
+    }
+}
+
+async fn get_run_impl(scheduler: &Scheduler, id: u64) -> Result<impl Reply, HttpError> {
+    let run = scheduler
+        .find_run(id)
+        .ok_or(HttpErrorKind::NoSuchRun)
+        .status(StatusCode::NOT_FOUND)?;
+    Ok(warp::reply::json(&RunData::from(&*run)))
+}
+// }}}
+// }}}
+
 // {{{ serve_image - serve an image from db info
 /// Serve an image using information given.
+///
+/// If a width and format are given, a variant of the image scaled down to that
+/// width is served instead, unless the image can not be resized.
 async fn serve_image(
-    birb_dir: &PathBuf,
-    (id, hash, permalink, content_type): (u32, Vec<u8>, String, String),
-) -> Result<Response<Vec<u8>>, HttpError> {
+    storage: &dyn Storage,
+    Image {
+        id,
+        hash,
+        permalink,
+        content_type,
+        fetched_at,
+        ..
+    }: Image,
+    variant: Option<(u32, Format)>,
+    cache_control: &str,
+    conditional: &Conditional,
+) -> Result<Response<Body>, HttpError> {
     let hex = hex::encode_upper(hash);
-    let file = birb_dir.join(&hex);
+    let variant = variant.filter(|_| thumbnail::supported(&content_type));
+    let (key, content_type) = match variant {
+        Some((width, format)) => (
+            thumbnail::key(&hex, width, format),
+            format.content_type().to_owned(),
+        ),
+        None => (hex.clone(), content_type),
+    };
+
+    // The key is derived from the hash of the original, so it identifies the
+    // exact bytes served.
+    let etag = format!(r#""{}""#, key);
+    let last_modified = fetched_at.map(|time| Utc.timestamp(time, 0));
+
+    let mut response = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::CACHE_CONTROL, cache_control);
+    if let Some(last_modified) = last_modified {
+        response = response.header(header::LAST_MODIFIED, http_date(last_modified));
+    }
+    if conditional.not_modified(&etag, last_modified) {
+        return response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .status(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    if let Some((width, format)) = variant {
+        thumbnail::generate(storage, &hex, width, format)
+            .await
+            .status(StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+    let size = storage
+        .size(&key)
+        .await
+        .status(StatusCode::INTERNAL_SERVER_ERROR)?;
 
     let extension = crate::utils::CONTENT_TYPE_EXTENSIONS
         .get(content_type.as_str())
         .copied()
         .unwrap_or("bin");
 
-    Response::builder()
-        .header(warp::http::header::CONTENT_TYPE, content_type)
-        .header(warp::http::header::SET_COOKIE, cookie!("Id" = id))
-        .header(
-            warp::http::header::SET_COOKIE,
-            cookie!("Permalink" = permalink),
-        )
-        .header(warp::http::header::SET_COOKIE, cookie!("Hash" = hex))
+    let response = response
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::SET_COOKIE, cookie!("Id" = id))
+        .header(header::SET_COOKIE, cookie!("Permalink" = permalink))
+        .header(header::SET_COOKIE, cookie!("Hash" = hex))
         .header(
-            warp::http::header::CONTENT_DISPOSITION,
+            header::CONTENT_DISPOSITION,
             format!(r#"inline; filename="{}.{}""#, id, extension),
-        )
-        .body(fs::read(file).status(StatusCode::INTERNAL_SERVER_ERROR)?)
-        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        );
+
+    let ranges = match conditional.ranges(&etag, last_modified, size) {
+        Some(ranges) => ranges,
+        None => {
+            let body = storage
+                .stream(&key)
+                .await
+                .status(StatusCode::INTERNAL_SERVER_ERROR)?;
+            return response
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::CONTENT_LENGTH, size)
+                .body(Body::wrap_stream(body))
+                .status(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    match *ranges.as_slice() {
+        [] => response
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{}", size))
+            .body(Body::empty())
+            .status(StatusCode::INTERNAL_SERVER_ERROR),
+        [range] => {
+            let body = storage
+                .stream_range(&key, range.start, range.end)
+                .await
+                .status(StatusCode::INTERNAL_SERVER_ERROR)?;
+            response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::CONTENT_RANGE, range.content_range(size))
+                .header(header::CONTENT_LENGTH, range.len())
+                .body(Body::wrap_stream(body))
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        _ => {
+            // Multiple ranges are sent as parts of a `multipart/byteranges`
+            // body, each with their own headers.
+            let boundary = hex::encode(rand::random::<[u8; 16]>());
+            let mut parts: Vec<ByteStream> = Vec::with_capacity(ranges.len() * 2 + 1);
+            let mut length = 0;
+            for range in ranges {
+                let head = format!(
+                    "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                    boundary,
+                    content_type,
+                    range.content_range(size),
+                );
+                length += head.len() as u64 + range.len();
+                parts.push(Box::pin(stream::once(future::ok(head.into()))));
+                parts.push(
+                    storage
+                        .stream_range(&key, range.start, range.end)
+                        .await
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)?,
+                );
+            }
+            let tail = format!("\r\n--{}--\r\n", boundary);
+            length += tail.len() as u64;
+            parts.push(Box::pin(stream::once(future::ok(tail.into()))));
+
+            response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(
+                    header::CONTENT_TYPE,
+                    format!("multipart/byteranges; boundary={}", boundary),
+                )
+                .header(header::CONTENT_LENGTH, length)
+                .body(Body::wrap_stream(stream::iter(parts).flatten()))
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
 }
 // }}}
 
@@ -231,6 +912,9 @@ pub async fn handle_rejection(rej: Rejection) -> Result<impl Reply, Infallible>
     if let Some(err) = rej.find::<HttpError>() {
         code = err.status;
         message = err.source.to_string();
+    } else if rej.find::<warp::reject::InvalidQuery>().is_some() {
+        code = StatusCode::BAD_REQUEST;
+        message = "INVALID_QUERY".into();
     } else if rej.is_not_found() {
         code = StatusCode::NOT_FOUND;
         message = "NOT_FOUND".into();
@@ -249,3 +933,146 @@ pub async fn handle_rejection(rej: Rejection) -> Result<impl Reply, Infallible>
     ))
 }
 // }}}
+
+// {{{ Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ETAG: &str = r#""abcdef""#;
+    const LAST_MODIFIED: &str = "Sun, 13 Sep 2020 12:26:40 GMT";
+
+    fn last_modified() -> Option<DateTime<Utc>> {
+        Some(Utc.timestamp(1_600_000_000, 0))
+    }
+
+    fn conditional(
+        if_none_match: Option<&str>,
+        if_modified_since: Option<&str>,
+        range: Option<&str>,
+        if_range: Option<&str>,
+    ) -> Conditional {
+        Conditional {
+            if_none_match: if_none_match.map(String::from),
+            if_modified_since: if_modified_since.map(String::from),
+            range: range.map(String::from),
+            if_range: if_range.map(String::from),
+        }
+    }
+
+    fn bounds(ranges: Option<Vec<ByteRange>>) -> Option<Vec<(u64, u64)>> {
+        ranges.map(|ranges| ranges.iter().map(|r| (r.start, r.end)).collect())
+    }
+
+    #[test]
+    fn byte_ranges_are_parsed() {
+        let cases = [
+            ("bytes=0-499", 1000, Some(vec![(0, 499)])),
+            ("bytes=500-", 1000, Some(vec![(500, 999)])),
+            ("bytes=-200", 1000, Some(vec![(800, 999)])),
+            ("bytes=-2000", 1000, Some(vec![(0, 999)])),
+            ("bytes=900-1999", 1000, Some(vec![(900, 999)])),
+            ("bytes=0-0,-1", 1000, Some(vec![(0, 0), (999, 999)])),
+            (" bytes=1-2 , 4-5 ", 1000, Some(vec![(1, 2), (4, 5)])),
+            // Unsatisfiable ranges are left out.
+            ("bytes=1000-1999", 1000, Some(vec![])),
+            ("bytes=1000-", 1000, Some(vec![])),
+            ("bytes=-0", 1000, Some(vec![])),
+            ("bytes=2000-,0-1", 1000, Some(vec![(0, 1)])),
+            ("bytes=0-499", 0, Some(vec![])),
+            ("bytes=-1", 0, Some(vec![])),
+            // Invalid headers are ignored altogether.
+            ("bytes=500-400", 1000, None),
+            ("bytes=0-1,5-4", 1000, None),
+            ("bytes=0-1,", 1000, None),
+            ("bytes=a-b", 1000, None),
+            ("bytes=5", 1000, None),
+            ("items=0-1", 1000, None),
+        ];
+        for (header, size, expected) in &cases {
+            let ranges = bounds(ByteRange::parse(header, *size));
+            assert_eq!(&ranges, expected, "{:?} of {} bytes", header, size);
+        }
+    }
+
+    #[test]
+    fn ranges_are_limited() {
+        let specs = |n| {
+            let specs: Vec<_> = (0..n)
+                .map(|i| format!("{}-{}", i * 10, i * 10 + 1))
+                .collect();
+            format!("bytes={}", specs.join(","))
+        };
+
+        let most = specs(MAX_RANGES);
+        let most = conditional(None, None, Some(&most), None);
+        let ranges = most.ranges(ETAG, last_modified(), 1000);
+        assert_eq!(ranges.map(|ranges| ranges.len()), Some(MAX_RANGES));
+
+        let many = specs(MAX_RANGES + 1);
+        let many = conditional(None, None, Some(&many), None);
+        assert!(many.ranges(ETAG, last_modified(), 1000).is_none());
+
+        let none = conditional(None, None, None, Some(ETAG));
+        assert!(none.ranges(ETAG, last_modified(), 1000).is_none());
+
+        // Serving none of the ranges is left to the caller.
+        let past = conditional(None, None, Some("bytes=1000-"), None);
+        assert_eq!(
+            bounds(past.ranges(ETAG, last_modified(), 1000)),
+            Some(vec![])
+        );
+    }
+
+    #[test]
+    fn ranges_are_only_served_if_range_matches() {
+        let cases = [
+            (ETAG, last_modified(), true),
+            (r#""other""#, last_modified(), false),
+            // Weak entity tags never match.
+            (r#"W/"abcdef""#, last_modified(), false),
+            (LAST_MODIFIED, last_modified(), true),
+            ("Sun, 13 Sep 2020 12:26:41 GMT", last_modified(), false),
+            ("Sun, 13 Sep 2020 12:26:39 GMT", last_modified(), false),
+            (LAST_MODIFIED, None, false),
+            ("yesterday", last_modified(), false),
+        ];
+        for (if_range, last_modified, served) in &cases {
+            let conditional = conditional(None, None, Some("bytes=0-9"), Some(if_range));
+            let ranges = bounds(conditional.ranges(ETAG, *last_modified, 1000));
+            let expected = if *served { Some(vec![(0, 9)]) } else { None };
+            assert_eq!(ranges, expected, "If-Range: {}", if_range);
+        }
+    }
+
+    #[test]
+    fn not_modified_checks_entity_tags_before_dates() {
+        let earlier = "Sun, 13 Sep 2020 12:26:39 GMT";
+        let later = "Sun, 13 Sep 2020 12:26:41 GMT";
+        let cases = [
+            (None, None, last_modified(), false),
+            (Some(ETAG), None, last_modified(), true),
+            (Some(r#"W/"abcdef""#), None, None, true),
+            (Some(r#""other", "abcdef""#), None, None, true),
+            (Some("*"), None, None, true),
+            (Some(r#""other""#), None, None, false),
+            (Some(r#""other""#), Some(later), last_modified(), false),
+            (None, Some(LAST_MODIFIED), last_modified(), true),
+            (None, Some(later), last_modified(), true),
+            (None, Some(earlier), last_modified(), false),
+            (None, Some(LAST_MODIFIED), None, false),
+            (None, Some("yesterday"), last_modified(), false),
+        ];
+        for (if_none_match, if_modified_since, last_modified, expected) in &cases {
+            let conditional = conditional(*if_none_match, *if_modified_since, None, None);
+            assert_eq!(
+                conditional.not_modified(ETAG, *last_modified),
+                *expected,
+                "If-None-Match: {:?}, If-Modified-Since: {:?}",
+                if_none_match,
+                if_modified_since
+            );
+        }
+    }
+}
+// }}}
@@ -0,0 +1,168 @@
+// birbfetcher - Collect bird images with ease.
+// Copyright (C) 2020-2021 Mariell Hoversholm
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::config::Subreddit;
+use crate::prelude::*;
+use crate::scheduler::{Job, Progress};
+use crate::storage::Storage;
+use crate::tasks;
+use async_trait::async_trait;
+use futures::lock::Mutex;
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// Fetch the posts of every subreddit whose interval has passed.
+pub struct FetchJob {
+    pub db: Arc<dyn Repository>,
+    pub storage: Arc<dyn Storage>,
+
+    /// How many pages deep to fetch every listing.
+    pub pages: usize,
+
+    /// When every subreddit was last fetched.
+    pub fetched_at: Mutex<HashMap<String, Instant>>,
+}
+
+#[async_trait]
+impl Job for FetchJob {
+    async fn run(&self, progress: &Progress) -> Result<(), JobError> {
+        // The configuration may have been reloaded since the last run.
+        let config = crate::config::current();
+        let mut fetched_at = self.fetched_at.lock().await;
+        let due = config
+            .subreddits
+            .iter()
+            .filter(|sub| {
+                fetched_at
+                    .get(&sub.name)
+                    .is_none_or(|at| at.elapsed() >= sub.interval)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        if due.is_empty() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        tasks::fetch_posts(&*self.db, &*self.storage, &due, self.pages, progress).await;
+        for sub in due {
+            fetched_at.insert(sub.name, now);
+        }
+        Ok(())
+    }
+}
+
+/// Fetch the posts of the given subreddits right away, regardless of their
+/// intervals.
+pub struct FetchNowJob {
+    pub db: Arc<dyn Repository>,
+    pub storage: Arc<dyn Storage>,
+    pub pages: usize,
+    pub subreddits: Vec<Subreddit>,
+}
+
+#[async_trait]
+impl Job for FetchNowJob {
+    async fn run(&self, progress: &Progress) -> Result<(), JobError> {
+        tasks::fetch_posts(
+            &*self.db,
+            &*self.storage,
+            &self.subreddits,
+            self.pages,
+            progress,
+        )
+        .await;
+        Ok(())
+    }
+}
+
+/// Backfill every subreddit.
+///
+/// Subreddits are backfilled once, but an interrupted backfill continues where
+/// it left off.
+pub struct BackfillJob {
+    pub db: Arc<dyn Repository>,
+    pub storage: Arc<dyn Storage>,
+}
+
+#[async_trait]
+impl Job for BackfillJob {
+    async fn run(&self, progress: &Progress) -> Result<(), JobError> {
+        let config = crate::config::current();
+        progress.set_total(config.subreddits.len() as u64);
+        for sub in &config.subreddits {
+            tasks::backfill(&*self.db, &*self.storage, sub).await;
+            progress.advance();
+        }
+        Ok(())
+    }
+}
+
+/// Check every unverified image's post, verifying or banning it.
+pub struct VerifyJob {
+    pub db: Arc<dyn Repository>,
+}
+
+#[async_trait]
+impl Job for VerifyJob {
+    async fn run(&self, progress: &Progress) -> Result<(), JobError> {
+        let mut curr_id = 0;
+        while let Some((id, permalink)) = self.db.next_unverified(curr_id).await? {
+            curr_id = id;
+            if let Err(e) = tasks::process_checking(&*self.db, id, &permalink).await {
+                error!("Error when processing {} ({}): {}", id, permalink, e);
+            }
+            progress.advance();
+        }
+        Ok(())
+    }
+}
+
+/// Probe the media info of every image fetched before it was recorded.
+///
+/// Once every image has been probed, this only has images left whose files
+/// could not be read.
+pub struct ProbeJob {
+    pub db: Arc<dyn Repository>,
+    pub storage: Arc<dyn Storage>,
+}
+
+#[async_trait]
+impl Job for ProbeJob {
+    async fn run(&self, progress: &Progress) -> Result<(), JobError> {
+        let mut curr_id = 0;
+        while let Some(image) = self.db.next_without_media_info(curr_id).await? {
+            curr_id = image.id;
+            let hash = hex::encode_upper(&image.hash);
+            // Objects may not be files we can probe in place, e.g. in S3.
+            let file = async {
+                let data = self.storage.get(&hash).await?;
+                let file = tempfile::NamedTempFile::new()?;
+                tokio::fs::write(file.path(), &data).await?;
+                Ok::<_, StorageError>(file)
+            };
+            match file.await {
+                Ok(file) => {
+                    let media = crate::media::probe(&image.content_type, file.path()).await;
+                    self.db.set_media_info(image.id, &media).await?;
+                }
+                Err(e) => error!("Could not read image {} to probe it: {}", image.id, e),
+            }
+            progress.advance();
+        }
+        Ok(())
+    }
+}
@@ -14,23 +14,34 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+mod config;
+mod database;
 mod discord;
+mod download;
 mod error;
 mod http;
+mod jobs;
+mod media;
 mod migrations;
+mod phash;
 mod reddit;
+mod scheduler;
+mod storage;
+mod strip;
 mod tasks;
+mod thumbnail;
 mod utils;
+mod video;
 
 mod prelude {
+    pub use crate::database::Repository;
     pub use crate::error::*;
     pub use log::{debug, error, info, trace, warn};
-    pub use sqlx::prelude::*;
-    pub use sqlx::MySqlPool;
     pub use std::sync::Arc;
 }
 
 use self::prelude::*;
+use self::scheduler::{Schedule, Scheduler};
 use anyhow::{Context as _, Result};
 use once_cell::sync::Lazy;
 use reqwest::Client as ReqwestClient;
@@ -39,9 +50,7 @@ use serenity::framework::standard::StandardFramework;
 use std::collections::{HashMap, HashSet};
 use std::env;
 use std::net::SocketAddr;
-use std::path::PathBuf;
 use std::time::Duration;
-use strum::IntoEnumIterator as _;
 use warp::Filter as _;
 
 /// An asynchronous reqwest client for HTTP requests.
@@ -104,78 +113,24 @@ async fn err_main() -> Result<()> {
     pretty_env_logger::try_init()?;
 
     let db = env::var("DATABASE_URL").context("`DATABASE_URL` must be set")?;
-    info!("Connecting to database...");
-    let pool = MySqlPool::new(&db).await?;
-
-    // {{{ Database migrations
-    // Make sure the table exists before we try to modify and fetch from it.
-    // We use a TINYINT(0) DEFAULT 0 because that makes sure only 1 row exists, ever.
-    sqlx::query(
-        r#"
-CREATE TABLE IF NOT EXISTS `meta_version`
-(
-    `key` TINYINT(0) NOT NULL DEFAULT 0,
-    `version` INT UNSIGNED NOT NULL,
-
-    PRIMARY KEY (`key`)
-)
-    "#,
-    )
-    .execute(&pool)
-    .await?;
-    // If we fail inserting, just move along. We don't really care.
-    sqlx::query("INSERT IGNORE INTO `meta_version` (`version`) VALUES (0)")
-        .execute(&pool)
-        .await?;
-
-    // Fetch the version; no limit because there may only be 1 row.
-    let version: (u32,) = sqlx::query_as("SELECT `version` FROM `meta_version`")
-        .fetch_one(&pool)
-        .await?;
-    let version = version.0 as u32;
-
-    debug!("Found DB version: {}", version);
-
-    // Let's use a transaction to be able to rollback.
-    let mut tx = pool.begin().await?;
-    for migration in self::migrations::Migrations::iter()
-        // We only want to migrate if the version is currently below that which we want to
-        // migrate up to. I.e. if we're version 1, we don't want to migrate to version 1.
-        .filter(|mig| (*mig as u32) > version)
-    {
-        // Migrations is #[repr(u32)].
-        let ver = migration as u32;
-        debug!("Applying migration to V{}", ver);
-
-        // Queries are limited by ;s.
-        for query in migration.queries() {
-            // If this query fails, the transaction is rolled back.
-            sqlx::query(&query).execute(&mut tx).await?;
-        }
-
-        // If this query fails, the transaction is rolled back.
-        sqlx::query(&format!("UPDATE `meta_version` SET `version` = {}", ver))
-            .execute(&mut tx)
-            .await?;
-        debug!("Migration for V{} successful.", ver);
-    }
-    debug!("Committing transaction...");
-    tx.commit().await?;
-    debug!("Migrations finished!");
-    // }}}
+    let pool = self::database::connect(&db).await?;
+    pool.migrate().await?;
 
     info!("Database connection created, and migrations finished!");
 
-    let birb_dir = env::var("BIRB_DIRECTORY").unwrap_or_else(|_| "birbs".into());
-    let birb_dir = PathBuf::from(birb_dir);
-    if birb_dir.metadata().is_err() {
-        // Metadata should only fail if there is no such dir.
-        std::fs::create_dir_all(&birb_dir)?;
-    }
+    let storage = self::storage::from_env().await?;
 
-    let subreddits = env::var("SUBREDDITS")
-        .map(|l| l.split(',').map(str::to_owned).collect::<Vec<_>>())
-        .unwrap_or_else(|_| vec!["birbs".into(), "parrots".into(), "birb".into()]);
+    self::config::reload()?;
+    tokio::spawn(async {
+        if let Err(e) = self::config::reload_on_hangup().await {
+            error!("Configuration will not be reloaded: {:?}", e);
+        }
+    });
+    let listing_pages = match env::var("LISTING_PAGES") {
+        Ok(pages) => pages.parse().context("`LISTING_PAGES` must be a number")?,
+        Err(_) => 1,
+    };
+    let backfill = env::var("BACKFILL").map_or(true, |b| b != "false");
 
     // {{{ Discord bot
     // TODO(Proximyst): Replace with API and separate bot/UI
@@ -221,104 +176,106 @@ CREATE TABLE IF NOT EXISTS `meta_version`
     });
     // }}}
 
-    // {{{ Fetch posts every 10 min timer
-    let timer_pool = pool.clone();
-    let timer_birb_dir = birb_dir.clone();
-    tokio::spawn(async move {
-        let mut timer = async_timer::Interval::platform_new(Duration::from_secs(600));
-        let subreddits = subreddits;
-        let pool = timer_pool;
-        let birb_dir = timer_birb_dir;
-
-        loop {
-            tasks::fetch_posts(&pool, &birb_dir, &subreddits).await;
-            timer.as_mut().await;
-        }
-    });
-    // }}}
-
-    // {{{ Verify posts every 5s timer
-    let timer_pool = pool.clone();
-    tokio::spawn(async move {
-        let mut timer = async_timer::Interval::platform_new(Duration::from_secs(5));
-        let mut curr_id = 0u32;
-        let pool = timer_pool;
-
-        loop {
-            let query = sqlx::query_as(
-                r#"
-                SELECT `id`, `permalink`
-                FROM `birbs`
-                WHERE `banned` = false
-                    AND `verified` = false
-                    AND `id` > ?
-                ORDER BY `id` ASC
-                LIMIT 1"#,
-            )
-            .bind(curr_id)
-            .fetch_optional(&pool)
-            .await;
-            match query {
-                Err(e) => error!("Could not find next ID to verify: {}", e),
-                Ok(res) => {
-                    match res {
-                        None => {
-                            curr_id = 0;
-                            tokio::time::delay_for(Duration::from_secs(600)).await;
-                            continue;
-                        }
-                        Some((id, permalink)) => {
-                            // Infer the type of the result.
-                            let (id, permalink): (u32, String) = (id, permalink);
-                            curr_id = id;
-
-                            if let Err(e) = tasks::process_checking(&pool, id, &permalink).await {
-                                error!("Error when processing {} ({}): {}", id, permalink, e);
-                            }
-                        }
-                    }
-                }
-            }
-            timer.as_mut().await;
-        }
-    });
+    // {{{ Scheduled jobs
+    let mut scheduler = Scheduler::new(pool.clone());
+    scheduler.add(
+        "fetch",
+        Schedule::Every(Duration::from_secs(60)),
+        self::jobs::FetchJob {
+            db: pool.clone(),
+            storage: storage.clone(),
+            pages: listing_pages,
+            fetched_at: Default::default(),
+        },
+    );
+    if backfill {
+        scheduler.add(
+            "backfill",
+            Schedule::Every(Duration::from_secs(3600)),
+            self::jobs::BackfillJob {
+                db: pool.clone(),
+                storage: storage.clone(),
+            },
+        );
+    }
+    scheduler.add(
+        "verify",
+        Schedule::Every(Duration::from_secs(600)),
+        self::jobs::VerifyJob { db: pool.clone() },
+    );
+    scheduler.add(
+        "probe",
+        Schedule::Every(Duration::from_secs(24 * 3600)),
+        self::jobs::ProbeJob {
+            db: pool.clone(),
+            storage: storage.clone(),
+        },
+    );
+    let scheduler = Arc::new(scheduler);
+    tokio::spawn(scheduler.clone().run());
     // }}}
 
     // {{{ GET / - random image
     let root_pool = pool.clone();
-    let root_birb_dir = birb_dir.clone();
-    let root = warp::get().and(warp::path::end()).and_then(move || {
-        let pool = root_pool.clone();
-        let birb_dir = root_birb_dir.clone();
-        async move { self::http::random_image(&pool, &birb_dir).await }
-    });
+    let root_storage = storage.clone();
+    let root = warp::get()
+        .and(warp::path::end())
+        .and(warp::query::<self::http::RandomQuery>())
+        .and(self::http::conditional())
+        .and_then(move |query, conditional| {
+            let pool = root_pool.clone();
+            let storage = root_storage.clone();
+            async move { self::http::random_image(&*pool, &*storage, query, conditional).await }
+        });
     // }}}
 
     // {{{ GET /random/image - random image
     let random_pool = pool.clone();
-    let random_birb_dir = birb_dir.clone();
+    let random_storage = storage.clone();
     let random = warp::get()
         .and(warp::path("random"))
         .and(warp::path("image"))
         .and(warp::path::end())
-        .and_then(move || {
+        .and(warp::query::<self::http::RandomQuery>())
+        .and(self::http::conditional())
+        .and_then(move |query, conditional| {
             let pool = random_pool.clone();
-            let birb_dir = random_birb_dir.clone();
-            async move { self::http::random_image(&pool, &birb_dir).await }
+            let storage = random_storage.clone();
+            async move { self::http::random_image(&*pool, &*storage, query, conditional).await }
         });
     // }}}
 
     // {{{ GET /id/:id - get image by id if unbanned
     let get_by_id_pool = pool.clone();
-    let get_by_id_birb_dir = birb_dir.clone();
+    let get_by_id_storage = storage.clone();
     let get_by_id = warp::get()
         .and(warp::path("id"))
         .and(warp::path::param())
         .and(warp::path::end())
-        .and_then(move |id: u32| {
+        .and(warp::query::<self::http::ResizeQuery>())
+        .and(self::http::conditional())
+        .and_then(move |id: u32, query, conditional| {
             let pool = get_by_id_pool.clone();
-            let birb_dir = get_by_id_birb_dir.clone();
-            async move { self::http::get_by_id(&pool, &birb_dir, id).await }
+            let storage = get_by_id_storage.clone();
+            async move { self::http::get_by_id(&*pool, &*storage, id, query, conditional).await }
+        });
+    // }}}
+
+    // {{{ GET /thumb/:id - get thumbnail of image by id if unbanned
+    let get_thumbnail_pool = pool.clone();
+    let get_thumbnail_storage = storage.clone();
+    let get_thumbnail = warp::get()
+        .and(warp::path("thumb"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::query::<self::http::ResizeQuery>())
+        .and(self::http::conditional())
+        .and_then(move |id: u32, query, conditional| {
+            let pool = get_thumbnail_pool.clone();
+            let storage = get_thumbnail_storage.clone();
+            async move {
+                self::http::get_thumbnail(&*pool, &*storage, id, query, conditional).await
+            }
         });
     // }}}
 
@@ -328,9 +285,11 @@ CREATE TABLE IF NOT EXISTS `meta_version`
         .and(warp::path("info"))
         .and(warp::path("random"))
         .and(warp::path::end())
-        .and_then(move || {
+        .and(warp::query::<self::http::RandomQuery>())
+        .and(self::http::conditional())
+        .and_then(move |query, conditional| {
             let pool = get_random_info_pool.clone();
-            async move { self::http::get_random_info(&pool).await }
+            async move { self::http::get_random_info(&*pool, query, conditional).await }
         });
     // }}}
 
@@ -341,17 +300,90 @@ CREATE TABLE IF NOT EXISTS `meta_version`
         .and(warp::path("id"))
         .and(warp::path::param())
         .and(warp::path::end())
-        .and_then(move |id: u32| {
+        .and(self::http::conditional())
+        .and_then(move |id: u32, conditional| {
             let pool = get_info_by_id_pool.clone();
-            async move { self::http::get_info_by_id(&pool, id).await }
+            async move { self::http::get_info_by_id(&*pool, id, conditional).await }
+        });
+    // }}}
+
+    // {{{ GET /list - list image info
+    let list_images_pool = pool.clone();
+    let list_images = warp::get()
+        .and(warp::path("list"))
+        .and(warp::path::end())
+        .and(warp::query::<self::http::ListQuery>())
+        .and_then(move |query| {
+            let pool = list_images_pool.clone();
+            async move { self::http::list_images(&*pool, query).await }
+        });
+    // }}}
+
+    // {{{ POST /admin/jobs/fetch(/:subreddit) - fetch posts now
+    let fetch_now_scheduler = scheduler.clone();
+    let fetch_now_pool = pool.clone();
+    let fetch_now_storage = storage.clone();
+    let fetch_now = warp::post()
+        .and(warp::path("admin"))
+        .and(warp::path("jobs"))
+        .and(warp::path("fetch"))
+        .and(
+            warp::path::param()
+                .map(Some)
+                .or(warp::any().map(|| None))
+                .unify(),
+        )
+        .and(warp::path::end())
+        .and(self::http::admin())
+        .and_then(move |subreddit: Option<String>| {
+            let scheduler = fetch_now_scheduler.clone();
+            let pool = fetch_now_pool.clone();
+            let storage = fetch_now_storage.clone();
+            async move {
+                self::http::fetch_now(&scheduler, pool, storage, listing_pages, subreddit).await
+            }
+        });
+    // }}}
+
+    // {{{ POST /admin/jobs/:name - run a scheduled job now
+    let trigger_job_scheduler = scheduler.clone();
+    let trigger_job = warp::post()
+        .and(warp::path("admin"))
+        .and(warp::path("jobs"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(self::http::admin())
+        .and_then(move |name: String| {
+            let scheduler = trigger_job_scheduler.clone();
+            async move { self::http::trigger_job(&scheduler, name).await }
+        });
+    // }}}
+
+    // {{{ GET /admin/jobs/runs/:id - get progress of a run
+    let get_run_scheduler = scheduler.clone();
+    let get_run = warp::get()
+        .and(warp::path("admin"))
+        .and(warp::path("jobs"))
+        .and(warp::path("runs"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(self::http::admin())
+        .and_then(move |id: u64| {
+            let scheduler = get_run_scheduler.clone();
+            async move { self::http::get_run(&scheduler, id).await }
         });
     // }}}
 
     warp::serve(
         root.or(random)
             .or(get_by_id)
+            .or(get_thumbnail)
             .or(get_random_info)
             .or(get_info_by_id)
+            .or(list_images)
+            .or(fetch_now)
+            .or(trigger_job)
+            .or(get_run)
             .recover(self::http::handle_rejection),
     )
     .run(
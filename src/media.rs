@@ -0,0 +1,120 @@
+// birbfetcher - Collect bird images with ease.
+// Copyright (C) 2020-2021 Mariell Hoversholm
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::database::MediaInfo;
+use crate::prelude::*;
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::io::Reader;
+use image::{AnimationDecoder, Frames, ImageDecoder, ImageFormat, ImageResult};
+use std::io::Cursor;
+use std::path::Path;
+
+/// Find out what the image or video in the file is like.
+///
+/// Whatever can't be decoded is left out; the size is always known unless the
+/// file can't be read at all.
+pub async fn probe(content_type: &str, path: &Path) -> MediaInfo {
+    let size = match tokio::fs::metadata(path).await {
+        Ok(metadata) => Some(metadata.len()),
+        Err(e) => {
+            warn!("Could not read file to probe it: {}", e);
+            return MediaInfo::default();
+        }
+    };
+    if let Some(format) = ImageFormat::from_mime_type(content_type) {
+        let data = match tokio::fs::read(path).await {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Could not read image to probe it: {}", e);
+                return MediaInfo {
+                    size,
+                    ..Default::default()
+                };
+            }
+        };
+        let info = tokio::task::spawn_blocking(move || probe_image(&data, format)).await;
+        let info = match info {
+            Ok(Ok(info)) => info,
+            Ok(Err(e)) => {
+                warn!("Could not decode image to probe it: {}", e);
+                MediaInfo::default()
+            }
+            Err(e) => {
+                warn!("Probing task failed: {}", e);
+                MediaInfo::default()
+            }
+        };
+        return MediaInfo { size, ..info };
+    }
+
+    // Only videos are left, which are always animated.
+    let info = crate::video::probe(path).await.unwrap_or_else(|e| {
+        warn!("Could not probe video: {}", e);
+        MediaInfo::default()
+    });
+    MediaInfo {
+        size,
+        animated: Some(true),
+        ..info
+    }
+}
+
+/// Read the dimensions and frame count of an image.
+///
+/// Frames are only decoded for formats which may be animated, and only to
+/// count them.
+fn probe_image(data: &[u8], format: ImageFormat) -> ImageResult<MediaInfo> {
+    let data = Cursor::new(data);
+    let ((width, height), frames) = match format {
+        ImageFormat::Gif => {
+            let decoder = GifDecoder::new(data)?;
+            (decoder.dimensions(), count_frames(decoder.into_frames())?)
+        }
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(data)?;
+            let dimensions = decoder.dimensions();
+            match decoder.is_apng() {
+                true => (dimensions, count_frames(decoder.apng().into_frames())?),
+                false => (dimensions, 1),
+            }
+        }
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(data)?;
+            let dimensions = decoder.dimensions();
+            match decoder.has_animation() {
+                true => (dimensions, count_frames(decoder.into_frames())?),
+                false => (dimensions, 1),
+            }
+        }
+        _ => (Reader::with_format(data, format).into_dimensions()?, 1),
+    };
+
+    Ok(MediaInfo {
+        width: Some(width),
+        height: Some(height),
+        frames: Some(frames),
+        animated: Some(frames > 1),
+        ..Default::default()
+    })
+}
+
+fn count_frames(frames: Frames<'_>) -> ImageResult<u32> {
+    frames
+        .into_iter()
+        .try_fold(0, |count, frame| frame.map(|_| count + 1))
+}
@@ -14,8 +14,20 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::database::Backend;
 use strum_macros::EnumIter;
 
+/// Include a migration file for the given backend.
+macro_rules! migration {
+    ($backend:expr, $file:literal) => {
+        match $backend {
+            Backend::MySql => include_str!(concat!("migrations/mysql/", $file)),
+            Backend::Postgres => include_str!(concat!("migrations/postgres/", $file)),
+            Backend::Sqlite => include_str!(concat!("migrations/sqlite/", $file)),
+        }
+    };
+}
+
 /// Migrations from one version of data to another.
 ///
 /// These define how to convert from old data to data we can process currently.
@@ -29,21 +41,51 @@ pub enum Migrations {
     V1 = 1,
     V2,
     V3,
+    V4,
+    V5,
+    V6,
+    V7,
+    V8,
+    V9,
+    V10,
 }
 
 impl Migrations {
+    /// Get the queries which make sure the `meta_version` table exists.
+    ///
+    /// These must be idempotent, as they are run on every start.
+    pub fn bootstrap(backend: Backend) -> Vec<String> {
+        split_queries(migration!(backend, "0000-meta-version.sql"))
+    }
+
     /// Get all queries with this migration.
-    pub fn queries(self) -> Vec<String> {
-        match self {
-            // TODO(Proximyst): Create macro for migrations
-            Self::V1 => include_str!("migrations/0001-create-tables.sql"),
-            Self::V2 => include_str!("migrations/0002-add-verified-column.sql"),
-            Self::V3 => include_str!("migrations/0003-unsigned-id-column.sql"),
-        }
+    pub fn queries(self, backend: Backend) -> Vec<String> {
+        split_queries(match self {
+            Self::V1 => migration!(backend, "0001-create-tables.sql"),
+            Self::V2 => migration!(backend, "0002-add-verified-column.sql"),
+            Self::V3 => migration!(backend, "0003-unsigned-id-column.sql"),
+            Self::V4 => migration!(backend, "0004-add-post-metadata.sql"),
+            Self::V5 => migration!(backend, "0005-create-backfills.sql"),
+            Self::V6 => migration!(backend, "0006-add-gallery-index.sql"),
+            Self::V7 => migration!(backend, "0007-create-jobs.sql"),
+            Self::V8 => migration!(backend, "0008-add-perceptual-hash.sql"),
+            Self::V9 => migration!(backend, "0009-add-media-info.sql"),
+            Self::V10 => migration!(backend, "0010-lowercase-subreddits.sql"),
+        })
+    }
+}
+
+/// Split a migration file into its queries.
+///
+/// Queries are limited by `;`s, and lines starting with `--` are comments.
+fn split_queries(file: &str) -> Vec<String> {
+    file.lines()
+        .filter(|l| !l.trim_start().starts_with("--"))
+        .collect::<Vec<_>>()
+        .join("\n")
         .split(';')
         .map(str::trim)
         .filter(|s| !s.is_empty())
         .map(str::to_string)
         .collect()
-    }
 }
@@ -0,0 +1,10 @@
+-- We use a TINYINT(0) DEFAULT 0 because that makes sure only 1 row exists, ever.
+CREATE TABLE IF NOT EXISTS `meta_version`
+(
+	`key` TINYINT(0) NOT NULL DEFAULT 0,
+	`version` INT UNSIGNED NOT NULL,
+
+	PRIMARY KEY (`key`)
+);
+-- If we fail inserting, just move along. We don't really care.
+INSERT IGNORE INTO `meta_version` (`version`) VALUES (0);
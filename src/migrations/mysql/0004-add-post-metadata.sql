@@ -0,0 +1,19 @@
+ALTER TABLE `birbs`
+ADD
+	`subreddit` VARCHAR(64) NULL,
+ADD
+	`title` VARCHAR(512) NULL,
+ADD
+	`author` VARCHAR(64) NULL,
+ADD
+	`posted_at` BIGINT NULL,
+ADD
+	`score` BIGINT NULL,
+ADD
+	`fetched_at` BIGINT NULL,
+ADD
+	INDEX (`subreddit`);
+-- Permalinks are always `/r/<subreddit>/comments/...`.
+UPDATE `birbs`
+SET `subreddit` = SUBSTRING_INDEX(SUBSTRING_INDEX(`permalink`, '/', 3), '/', -1)
+WHERE `permalink` LIKE '/r/%';
@@ -0,0 +1,9 @@
+CREATE TABLE `backfills`
+(
+	`subreddit` VARCHAR(64) NOT NULL,
+	`listing` VARCHAR(16) NOT NULL,
+	`next_page` VARCHAR(32) NULL,
+	`finished` BOOLEAN NOT NULL DEFAULT FALSE,
+
+	PRIMARY KEY (`subreddit`, `listing`)
+);
@@ -0,0 +1,4 @@
+-- Images of posts which are not galleries have no index.
+ALTER TABLE `birbs`
+ADD
+	`gallery_index` INT NULL;
@@ -0,0 +1,10 @@
+CREATE TABLE `jobs`
+(
+	`name` VARCHAR(64) NOT NULL,
+	`last_started_at` BIGINT NULL,
+	`last_finished_at` BIGINT NULL,
+	`next_run_at` BIGINT NULL,
+	`last_error` TEXT NULL,
+
+	PRIMARY KEY (`name`)
+);
@@ -0,0 +1,5 @@
+ALTER TABLE `birbs`
+ADD
+	`phash` BIGINT NULL,
+ADD
+	`duplicate_of` INT UNSIGNED NULL;
@@ -0,0 +1,3 @@
+-- Subreddits are compared without regard to case.
+UPDATE `birbs`
+SET `subreddit` = LOWER(`subreddit`);
@@ -0,0 +1,9 @@
+-- The key always defaults to 0, which makes sure only 1 row exists, ever.
+CREATE TABLE IF NOT EXISTS meta_version
+(
+	key SMALLINT NOT NULL DEFAULT 0 CHECK (key = 0),
+	version INTEGER NOT NULL,
+
+	PRIMARY KEY (key)
+);
+INSERT INTO meta_version (version) VALUES (0) ON CONFLICT DO NOTHING;
@@ -0,0 +1,12 @@
+CREATE TABLE birbs
+(
+	id SERIAL NOT NULL,
+	hash BYTEA NOT NULL,
+	permalink TEXT NOT NULL,
+	source_url VARCHAR(512) NOT NULL,
+	content_type VARCHAR(64) NOT NULL,
+	banned BOOLEAN NOT NULL DEFAULT FALSE,
+
+	PRIMARY KEY (id),
+	UNIQUE (hash)
+);
@@ -0,0 +1 @@
+-- Only MySQL had a signed ID column to begin with.
@@ -0,0 +1,18 @@
+ALTER TABLE birbs
+ADD
+	subreddit VARCHAR(64) NULL,
+ADD
+	title VARCHAR(512) NULL,
+ADD
+	author VARCHAR(64) NULL,
+ADD
+	posted_at BIGINT NULL,
+ADD
+	score BIGINT NULL,
+ADD
+	fetched_at BIGINT NULL;
+CREATE INDEX birbs_subreddit ON birbs (subreddit);
+-- Permalinks are always `/r/<subreddit>/comments/...`.
+UPDATE birbs
+SET subreddit = SPLIT_PART(permalink, '/', 3)
+WHERE permalink LIKE '/r/%';
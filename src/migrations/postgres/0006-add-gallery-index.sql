@@ -0,0 +1,2 @@
+-- Images of posts which are not galleries have no index.
+ALTER TABLE birbs ADD gallery_index INT NULL;
@@ -0,0 +1,14 @@
+-- Images fetched before these were recorded have none of them.
+ALTER TABLE birbs
+ADD
+	width INT NULL,
+ADD
+	height INT NULL,
+ADD
+	size BIGINT NULL,
+ADD
+	frames INT NULL,
+ADD
+	animated BOOLEAN NULL,
+ADD
+	duration_ms BIGINT NULL;
@@ -0,0 +1,10 @@
+-- The key always defaults to 0, which makes sure only 1 row exists, ever.
+CREATE TABLE IF NOT EXISTS meta_version
+(
+	key INTEGER NOT NULL DEFAULT 0 CHECK (key = 0),
+	version INTEGER NOT NULL,
+
+	PRIMARY KEY (key)
+);
+-- The key must be given, as an INTEGER PRIMARY KEY otherwise defaults to the row ID.
+INSERT OR IGNORE INTO meta_version (key, version) VALUES (0, 0);
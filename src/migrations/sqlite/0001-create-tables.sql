@@ -0,0 +1,11 @@
+CREATE TABLE birbs
+(
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	hash BLOB NOT NULL,
+	permalink TEXT NOT NULL,
+	source_url VARCHAR(512) NOT NULL,
+	content_type VARCHAR(64) NOT NULL,
+	banned BOOLEAN NOT NULL DEFAULT FALSE,
+
+	UNIQUE (hash)
+);
@@ -0,0 +1,3 @@
+ALTER TABLE birbs
+ADD
+	verified BOOLEAN NOT NULL DEFAULT FALSE;
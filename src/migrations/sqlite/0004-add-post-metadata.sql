@@ -0,0 +1,12 @@
+-- SQLite can only add a single column at a time.
+ALTER TABLE birbs ADD subreddit VARCHAR(64) NULL;
+ALTER TABLE birbs ADD title VARCHAR(512) NULL;
+ALTER TABLE birbs ADD author VARCHAR(64) NULL;
+ALTER TABLE birbs ADD posted_at BIGINT NULL;
+ALTER TABLE birbs ADD score BIGINT NULL;
+ALTER TABLE birbs ADD fetched_at BIGINT NULL;
+CREATE INDEX birbs_subreddit ON birbs (subreddit);
+-- Permalinks are always `/r/<subreddit>/comments/...`.
+UPDATE birbs
+SET subreddit = SUBSTR(permalink, 4, INSTR(SUBSTR(permalink, 4), '/') - 1)
+WHERE permalink LIKE '/r/%/%';
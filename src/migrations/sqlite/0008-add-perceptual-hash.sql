@@ -0,0 +1,3 @@
+-- SQLite can only add a single column at a time.
+ALTER TABLE birbs ADD phash BIGINT NULL;
+ALTER TABLE birbs ADD duplicate_of INT NULL;
@@ -0,0 +1,7 @@
+-- SQLite can only add a single column at a time.
+ALTER TABLE birbs ADD width INT NULL;
+ALTER TABLE birbs ADD height INT NULL;
+ALTER TABLE birbs ADD size BIGINT NULL;
+ALTER TABLE birbs ADD frames INT NULL;
+ALTER TABLE birbs ADD animated BOOLEAN NULL;
+ALTER TABLE birbs ADD duration_ms BIGINT NULL;
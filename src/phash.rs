@@ -0,0 +1,136 @@
+// birbfetcher - Collect bird images with ease.
+// Copyright (C) 2020-2021 Mariell Hoversholm
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::prelude::*;
+use futures::lock::Mutex;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+use once_cell::sync::Lazy;
+use std::env;
+use std::path::Path;
+use strum_macros::{Display, EnumString};
+
+/// What to do with images which are near-duplicates of stored ones.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Display, EnumString)]
+pub enum Mode {
+    /// Don't store them.
+    #[strum(serialize = "reject")]
+    Reject,
+
+    /// Store them, linked to the image they are a near-duplicate of.
+    #[strum(serialize = "link")]
+    Link,
+
+    /// Store them as any other image.
+    #[strum(serialize = "off")]
+    Off,
+}
+
+/// What to do with near-duplicates, set by `NEAR_DUPLICATES`.
+pub static MODE: Lazy<Mode> = Lazy::new(|| match env::var("NEAR_DUPLICATES") {
+    // Panicking is fine because only the env var may be invalid in this case
+    Ok(mode) => mode
+        .parse()
+        .expect("`NEAR_DUPLICATES` must be `reject`, `link` or `off`"),
+    Err(_) => Mode::Reject,
+});
+
+/// The largest Hamming distance between the hashes of images for them to be
+/// near-duplicates, set by `NEAR_DUPLICATE_DISTANCE`.
+static MAX_DISTANCE: Lazy<u32> = Lazy::new(|| match env::var("NEAR_DUPLICATE_DISTANCE") {
+    Ok(distance) => distance
+        .parse()
+        .expect("`NEAR_DUPLICATE_DISTANCE` must be a number"),
+    Err(_) => 4,
+});
+
+/// The IDs of stored images along with their hashes.
+type Hashes = Vec<(u32, u64)>;
+
+/// The hashes of every stored image, loaded when first needed.
+static HASHES: Lazy<Mutex<Option<Hashes>>> = Lazy::new(Default::default);
+
+/// Compute the difference hash of an image.
+///
+/// This compares the brightness of neighbouring pixels of a tiny greyscale
+/// version of the image, such that re-encoding or resizing the image barely
+/// changes it.
+pub fn dhash(image: &DynamicImage) -> u64 {
+    let small = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = small.get_pixel(x, y)[0] < small.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | brighter as u64;
+        }
+    }
+    hash
+}
+
+/// Compute the difference hash of the image in the file, if it is an image we
+/// can decode.
+pub async fn compute(content_type: &str, path: &Path) -> Option<u64> {
+    let format = ImageFormat::from_mime_type(content_type)?;
+    let data = match tokio::fs::read(path).await {
+        Ok(data) => data,
+        Err(e) => {
+            warn!("Could not read image to hash it: {}", e);
+            return None;
+        }
+    };
+    let hash = tokio::task::spawn_blocking(move || {
+        image::load_from_memory_with_format(&data, format).map(|image| dhash(&image))
+    })
+    .await;
+    match hash {
+        Ok(Ok(hash)) => Some(hash),
+        Ok(Err(e)) => {
+            warn!("Could not decode image to hash it: {}", e);
+            None
+        }
+        Err(e) => {
+            warn!("Hashing task failed: {}", e);
+            None
+        }
+    }
+}
+
+/// Find the stored image nearest to the hash, if it is close enough to be a
+/// near-duplicate.
+pub async fn find_near_duplicate(
+    db: &dyn Repository,
+    hash: u64,
+) -> Result<Option<u32>, sqlx::Error> {
+    let mut hashes = HASHES.lock().await;
+    if hashes.is_none() {
+        *hashes = Some(db.phashes().await?);
+    }
+
+    Ok(hashes
+        .iter()
+        .flatten()
+        .map(|&(id, other)| (id, (hash ^ other).count_ones()))
+        .filter(|&(_, distance)| distance <= *MAX_DISTANCE)
+        .min_by_key(|&(_, distance)| distance)
+        .map(|(id, _)| id))
+}
+
+/// Remember the hash of a newly stored image.
+pub async fn remember(id: u32, hash: u64) {
+    if let Some(ref mut hashes) = *HASHES.lock().await {
+        hashes.push((id, hash));
+    }
+}
@@ -16,22 +16,168 @@
 
 // TODO(Proximyst): Ugly file, needa redo this.
 
+mod hosts;
+mod oauth;
+mod ratelimit;
+
+use self::oauth::OAuth;
+use self::ratelimit::RateLimiter;
 use crate::prelude::*;
+use once_cell::sync::Lazy;
+use reqwest::{Response, StatusCode};
 use serde::Deserialize;
 use serde_json::Value as JsonValue;
-use strum_macros::Display;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+use strum_macros::{Display, EnumString};
 
 /// The base URL of the Reddit API.
-const REDDIT_API: &'static str = "https://reddit.com";
+const REDDIT_API: &str = "https://reddit.com";
+
+/// The base URL of the Reddit API for authenticated requests.
+const REDDIT_OAUTH_API: &str = "https://oauth.reddit.com";
+
+/// How many times to retry a request Reddit failed to respond to.
+const MAX_RETRIES: u32 = 5;
+
+/// The OAuth client, if we have credentials to authenticate with.
+static OAUTH: Lazy<Option<OAuth>> = Lazy::new(OAuth::from_env);
+
+/// The rate limiter shared by every request to the API.
+static RATE_LIMITER: Lazy<RateLimiter> = Lazy::new(RateLimiter::default);
+
+/// The base URL to send API requests to.
+///
+/// This depends on whether we authenticate, unless set by `REDDIT_API_URL`.
+static API_URL: Lazy<String> = Lazy::new(|| {
+    env::var("REDDIT_API_URL").unwrap_or_else(|_| {
+        match *OAUTH {
+            Some(_) => REDDIT_OAUTH_API,
+            None => REDDIT_API,
+        }
+        .into()
+    })
+});
+
+/// Send a GET request for the path to the Reddit API, authenticating if we
+/// have credentials.
+///
+/// Requests are held back by the rate limiter, and retried with backoff if
+/// Reddit is overloaded or we are sending too many.
+async fn get(path: &str) -> Result<Response, RedditError> {
+    let url = format!("{}{}", *API_URL, path);
+    let mut attempt = 0;
+    let mut reauthenticated = false;
+    loop {
+        RATE_LIMITER.acquire().await;
+        let mut req = crate::REQWEST_CLIENT.get(&url);
+        if let Some(ref oauth) = *OAUTH {
+            req = req.bearer_auth(oauth.token().await?);
+        }
+        let req = req.send().await?;
+        RATE_LIMITER.update(req.headers()).await;
+
+        let status = req.status();
+        match *OAUTH {
+            // The token may have been revoked before it expired; try again once.
+            Some(ref oauth) if status == StatusCode::UNAUTHORIZED && !reauthenticated => {
+                debug!("Reddit rejected our access token, requesting a new one...");
+                oauth.invalidate().await;
+                reauthenticated = true;
+            }
+            _ if (status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error())
+                && attempt < MAX_RETRIES =>
+            {
+                let delay = ratelimit::backoff(attempt, req.headers());
+                warn!(
+                    "Reddit responded with {} to {}, retrying in {:.1}s...",
+                    status,
+                    path,
+                    delay.as_secs_f64()
+                );
+                tokio::time::delay_for(delay).await;
+                attempt += 1;
+            }
+            _ => return Ok(req),
+        }
+    }
+}
+
+/// The time windows posts can be sorted by their score within.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Display, EnumString)]
+pub enum TimeWindow {
+    #[strum(serialize = "hour")]
+    Hour,
+
+    #[strum(serialize = "day")]
+    Day,
+
+    #[strum(serialize = "week")]
+    Week,
+
+    #[strum(serialize = "month")]
+    Month,
+
+    #[strum(serialize = "year")]
+    Year,
+
+    #[strum(serialize = "all")]
+    All,
+}
 
 /// The types of posts we can fetch.
-#[derive(Copy, Clone, Eq, PartialEq, Debug, Display)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum PostType {
-    #[strum(serialize = "new")]
     New,
-
-    #[strum(serialize = "hot")]
     Hot,
+    Rising,
+    Top(TimeWindow),
+    Controversial(TimeWindow),
+}
+
+impl fmt::Display for PostType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::New => f.write_str("new"),
+            Self::Hot => f.write_str("hot"),
+            Self::Rising => f.write_str("rising"),
+            Self::Top(window) => write!(f, "top/{}", window),
+            Self::Controversial(window) => write!(f, "controversial/{}", window),
+        }
+    }
+}
+
+impl FromStr for PostType {
+    type Err = strum::ParseError;
+
+    /// Parse a listing as displayed, e.g. `hot` or `top/week`.
+    ///
+    /// A sorted listing without a time window uses the past day, as Reddit
+    /// does.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, window) = match s.find('/') {
+            Some(idx) => (&s[..idx], Some(s[idx + 1..].parse()?)),
+            None => (s, None),
+        };
+        match (name, window) {
+            ("new", None) => Ok(Self::New),
+            ("hot", None) => Ok(Self::Hot),
+            ("rising", None) => Ok(Self::Rising),
+            ("top", window) => Ok(Self::Top(window.unwrap_or(TimeWindow::Day))),
+            ("controversial", window) => Ok(Self::Controversial(window.unwrap_or(TimeWindow::Day))),
+            _ => Err(strum::ParseError::VariantNotFound),
+        }
+    }
+}
+
+/// A page of a listing of posts.
+pub struct Listing {
+    pub posts: Vec<RedditPost>,
+
+    /// The cursor of the next page, if there is one.
+    pub after: Option<String>,
 }
 
 /// A data structure of Reddit posts.
@@ -64,14 +210,96 @@ pub struct RedditPost {
 
     #[serde(default)]
     pub created: f64,
+
+    #[serde(default)]
+    pub created_utc: f64,
+
+    #[serde(default = "String::new")]
+    pub title: String,
+
+    #[serde(default = "String::new")]
+    pub author: String,
+
+    pub link_flair_text: Option<String>,
+
+    #[serde(default)]
+    pub is_gallery: bool,
+
+    /// The order of the images of a gallery.
+    pub gallery_data: Option<GalleryData>,
+
+    /// The images of a gallery by their media ID.
+    pub media_metadata: Option<HashMap<String, MediaMetadata>>,
+
+    /// The video of the post if it is hosted by Reddit.
+    pub secure_media: Option<SecureMedia>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GalleryData {
+    pub items: Vec<GalleryItem>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GalleryItem {
+    pub media_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MediaMetadata {
+    /// Whether the media is still available, i.e. `valid`.
+    #[serde(default = "String::new")]
+    pub status: String,
+
+    /// The content type of the media, e.g. `image/jpg`.
+    #[serde(rename = "m")]
+    pub mime: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SecureMedia {
+    pub reddit_video: Option<RedditVideo>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RedditVideo {
+    /// A single MP4 file of the video, without audio.
+    pub fallback_url: String,
+
+    /// Whether this is a GIF converted to a video, i.e. it has no audio.
+    #[serde(default)]
+    pub is_gif: bool,
+
+    /// Whether the video has audio. Older posts don't say.
+    pub has_audio: Option<bool>,
+}
+
+/// A file of a post to fetch.
+#[derive(Debug, Clone)]
+pub struct Media {
+    pub url: String,
+
+    /// The position of the file within the post's gallery, if it is one.
+    pub gallery_index: Option<u32>,
+
+    /// The URL the audio track of a Reddit video can be found under, if it
+    /// may have one.
+    pub audio_base: Option<String>,
+}
+
+impl Media {
+    pub fn new(url: String, gallery_index: Option<u32>) -> Self {
+        Self {
+            url,
+            gallery_index,
+            audio_base: None,
+        }
+    }
 }
 
 pub async fn request_single_post(permalink: &str) -> Result<RedditPost, RedditError> {
     trace!("Requesting post for {}...", permalink);
-    let req = crate::REQWEST_CLIENT
-        .get(&format!("{}/{}.json", REDDIT_API, permalink,))
-        .send()
-        .await?;
+    let req = get(&format!("/{}.json", permalink.trim_start_matches('/'))).await?;
 
     if !req.status().is_success() {
         trace!("Got unsuccessful post for {}", permalink);
@@ -94,8 +322,7 @@ pub async fn request_single_post(permalink: &str) -> Result<RedditPost, RedditEr
     trace!("Deserializing post {} into container", permalink);
     let post: JsonValue = serde_json::from_str(&req.text().await?)?;
     let post = post.as_array()
-        .map(|p| p.first())
-        .flatten()
+        .and_then(|p| p.first())
         .cloned()
         .ok_or(RedditError::NoPost)?;
     let mut post: Post = serde_json::from_value(post)?;
@@ -103,18 +330,32 @@ pub async fn request_single_post(permalink: &str) -> Result<RedditPost, RedditEr
     trace!("Post {} properly fetched!", permalink);
     post.data.children.pop()
         .map(|p| p.data)
-        .ok_or(RedditError::NoPost.into())
+        .ok_or(RedditError::NoPost)
 }
 
-pub async fn request_posts(subreddit: &str, ty: PostType) -> Result<Vec<RedditPost>, RedditError> {
-    trace!("Requesting posts for {} type {}...", subreddit, ty);
-    let req = crate::REQWEST_CLIENT
-        .get(&format!(
-            "{}/r/{}/{}.json?limit=100",
-            REDDIT_API, subreddit, ty
-        ))
-        .send()
-        .await?;
+pub async fn request_posts(
+    subreddit: &str,
+    ty: PostType,
+    after: Option<&str>,
+) -> Result<Listing, RedditError> {
+    trace!(
+        "Requesting posts for {} type {} after {:?}...",
+        subreddit,
+        ty,
+        after
+    );
+    let mut path = match ty {
+        PostType::Top(window) => format!("/r/{}/top.json?t={}&limit=100", subreddit, window),
+        PostType::Controversial(window) => {
+            format!("/r/{}/controversial.json?t={}&limit=100", subreddit, window)
+        }
+        ty => format!("/r/{}/{}.json?limit=100", subreddit, ty),
+    };
+    if let Some(after) = after {
+        path.push_str("&after=");
+        path.push_str(after);
+    }
+    let req = get(&path).await?;
 
     if !req.status().is_success() {
         trace!("Got unsuccessful posts for {} & {}", ty, subreddit);
@@ -128,6 +369,7 @@ pub async fn request_posts(subreddit: &str, ty: PostType) -> Result<Vec<RedditPo
     #[derive(Deserialize)]
     struct PostContainer {
         children: Vec<PostContainerData>,
+        after: Option<String>,
     }
     #[derive(Deserialize)]
     struct Post {
@@ -142,7 +384,10 @@ pub async fn request_posts(subreddit: &str, ty: PostType) -> Result<Vec<RedditPo
     let post: Post = serde_json::from_str(&req.text().await?)?;
 
     trace!("Posts for {}/{} properly fetched!", subreddit, ty);
-    Ok(post.data.children.into_iter().map(|p| p.data).collect())
+    Ok(Listing {
+        posts: post.data.children.into_iter().map(|p| p.data).collect(),
+        after: post.data.after,
+    })
 }
 
 impl RedditPost {
@@ -158,7 +403,7 @@ impl RedditPost {
             }
             || self.score < 1
             || self.subreddit_type != "public"
-            || !self.is_url_safe()
+            || (self.media().is_empty() && hosts::resolver(&self.url).is_none())
     }
 
     #[inline(always)]
@@ -167,10 +412,84 @@ impl RedditPost {
     }
 
     pub fn is_url_safe(&self) -> bool {
-        !self.url.trim().is_empty()
-            && self.url.starts_with("https://i.redd.it/")
-            && [".jpg", ".jpeg", ".png", ".gif", ".gifv", ".webm"]
-                .iter()
-                .any(|ext| self.url.ends_with(ext))
+        is_url_safe(&self.url)
     }
+
+    /// Get the files of the post to fetch.
+    ///
+    /// Images of a gallery which are unavailable or of unsupported types are
+    /// left out, but the indices of the others stay the same.
+    pub fn media(&self) -> Vec<Media> {
+        if self.is_gallery {
+            return self.gallery();
+        }
+
+        let video = self
+            .secure_media
+            .as_ref()
+            .and_then(|media| media.reddit_video.as_ref());
+        if let Some(video) = video {
+            if !video.fallback_url.starts_with("https://v.redd.it/") {
+                return vec![];
+            }
+            // The tracks are next to each other, e.g. `https://v.redd.it/<id>/DASH_720.mp4`.
+            let base = &video.fallback_url[..video.fallback_url.rfind('/').unwrap_or_default()];
+            let mut media = Media::new(video.fallback_url.clone(), None);
+            if !video.is_gif && video.has_audio != Some(false) {
+                media.audio_base = Some(base.to_owned());
+            }
+            return vec![media];
+        }
+
+        match self.is_url_safe() {
+            true => vec![Media::new(self.url.clone(), None)],
+            false => vec![],
+        }
+    }
+
+    /// Get the files of the post to fetch, resolving links to external hosts
+    /// into the media they point to.
+    pub async fn resolve_media(&self) -> Result<Vec<Media>, ResolveError> {
+        let media = self.media();
+        if !media.is_empty() {
+            return Ok(media);
+        }
+        match hosts::resolver(&self.url) {
+            Some((resolver, url)) => resolver.resolve(&url).await,
+            None => Ok(vec![]),
+        }
+    }
+
+    fn gallery(&self) -> Vec<Media> {
+        let (items, metadata) = match (&self.gallery_data, &self.media_metadata) {
+            (Some(data), Some(metadata)) => (&data.items, metadata),
+            _ => return vec![],
+        };
+        items
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, item)| {
+                let media = metadata.get(&item.media_id)?;
+                if media.status != "valid" {
+                    return None;
+                }
+                // Reddit calls JPEGs `image/jpg`, which also matches its file names.
+                let ext = media.mime.as_deref()?.strip_prefix("image/")?;
+                let url = format!("https://i.redd.it/{}.{}", item.media_id, ext);
+                if !is_url_safe(&url) {
+                    return None;
+                }
+                Some(Media::new(url, Some(idx as u32)))
+            })
+            .collect()
+    }
+}
+
+/// Check whether the URL is one of a single image hosted by Reddit.
+fn is_url_safe(url: &str) -> bool {
+    !url.trim().is_empty()
+        && url.starts_with("https://i.redd.it/")
+        && [".jpg", ".jpeg", ".png", ".gif", ".gifv", ".webm"]
+            .iter()
+            .any(|ext| url.ends_with(ext))
 }
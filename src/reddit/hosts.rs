@@ -0,0 +1,201 @@
+// birbfetcher - Collect bird images with ease.
+// Copyright (C) 2020-2021 Mariell Hoversholm
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::Media;
+use crate::prelude::*;
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use reqwest::Url;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::env;
+
+/// The base URL of the Imgur API.
+const IMGUR_API: &str = "https://api.imgur.com";
+
+/// Every resolver we can use, in the order they are tried.
+static RESOLVERS: Lazy<Vec<Box<dyn HostResolver>>> = Lazy::new(|| {
+    let mut resolvers: Vec<Box<dyn HostResolver>> =
+        vec![Box::new(ImgurDirect), Box::new(RedditPreview)];
+    match env::var("IMGUR_CLIENT_ID") {
+        Ok(client_id) => resolvers.push(Box::new(ImgurAlbum {
+            client_id,
+            api_url: env::var("IMGUR_API_URL").unwrap_or_else(|_| IMGUR_API.into()),
+        })),
+        Err(_) => debug!("No `IMGUR_CLIENT_ID` is set; Imgur albums are skipped."),
+    }
+    resolvers
+});
+
+/// The hosts we may resolve media of, set by `ALLOWED_HOSTS`.
+///
+/// Every host a resolver supports is allowed unless configured otherwise.
+static ALLOWED_HOSTS: Lazy<HashSet<String>> = Lazy::new(|| match env::var("ALLOWED_HOSTS") {
+    Ok(hosts) => hosts
+        .split(',')
+        .map(|host| host.trim().to_lowercase())
+        .filter(|host| !host.is_empty())
+        .collect(),
+    Err(_) => RESOLVERS
+        .iter()
+        .flat_map(|resolver| resolver.hosts())
+        .map(|&host| host.to_owned())
+        .collect(),
+});
+
+/// A resolver of links to an external host into the media they point to.
+#[async_trait]
+pub trait HostResolver: Send + Sync {
+    /// The hosts this resolves links of.
+    fn hosts(&self) -> &'static [&'static str];
+
+    /// Check whether this resolves the link, given it is to one of its hosts.
+    fn matches(&self, url: &Url) -> bool;
+
+    /// Get the direct URLs of the media the link points to.
+    async fn resolve(&self, url: &Url) -> Result<Vec<Media>, ResolveError>;
+}
+
+/// Find the resolver of the link, if its host is allowed.
+pub fn resolver(url: &str) -> Option<(&'static dyn HostResolver, Url)> {
+    let url = Url::parse(url).ok().filter(|url| url.scheme() == "https")?;
+    let host = url.host_str()?;
+    if !ALLOWED_HOSTS.contains(host) {
+        return None;
+    }
+
+    RESOLVERS
+        .iter()
+        .find(|resolver| resolver.hosts().contains(&host) && resolver.matches(&url))
+        .map(|resolver| (&**resolver, url))
+}
+
+/// Direct links to images on Imgur, e.g. `https://i.imgur.com/<id>.png`.
+struct ImgurDirect;
+
+#[async_trait]
+impl HostResolver for ImgurDirect {
+    fn hosts(&self) -> &'static [&'static str] {
+        &["i.imgur.com"]
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        let path = url.path().trim_start_matches('/');
+        !path.contains('/')
+            && [".jpg", ".jpeg", ".png", ".gif", ".gifv", ".mp4"]
+                .iter()
+                .any(|ext| path.ends_with(ext))
+    }
+
+    async fn resolve(&self, url: &Url) -> Result<Vec<Media>, ResolveError> {
+        // `.gifv` links are web pages around the actual video.
+        let path = match url.path().strip_suffix(".gifv") {
+            Some(id) => format!("{}.mp4", id),
+            None => url.path().to_owned(),
+        };
+        Ok(vec![Media::new(
+            format!("https://i.imgur.com{}", path),
+            None,
+        )])
+    }
+}
+
+/// Albums on Imgur, e.g. `https://imgur.com/a/<id>`.
+///
+/// These are looked up through the Imgur API, which requires a client ID.
+struct ImgurAlbum {
+    client_id: String,
+    api_url: String,
+}
+
+#[async_trait]
+impl HostResolver for ImgurAlbum {
+    fn hosts(&self) -> &'static [&'static str] {
+        &["imgur.com", "m.imgur.com"]
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        album_id(url).is_some()
+    }
+
+    async fn resolve(&self, url: &Url) -> Result<Vec<Media>, ResolveError> {
+        let id = album_id(url).unwrap_or_default();
+        trace!("Requesting images of Imgur album {}...", id);
+        let req = crate::REQWEST_CLIENT
+            .get(&format!("{}/3/album/{}/images", self.api_url, id))
+            .header(
+                reqwest::header::AUTHORIZATION,
+                format!("Client-ID {}", self.client_id),
+            );
+        let resp = crate::download::fetch(req).await?;
+
+        #[derive(Deserialize)]
+        struct AlbumImage {
+            link: String,
+
+            /// The image as a video, if it is animated.
+            mp4: Option<String>,
+        }
+        #[derive(Deserialize)]
+        struct Album {
+            data: Vec<AlbumImage>,
+        }
+
+        let album: Album = serde_json::from_slice(&resp)?;
+        Ok(album
+            .data
+            .into_iter()
+            .enumerate()
+            .map(|(idx, image)| Media::new(image.mp4.unwrap_or(image.link), Some(idx as u32)))
+            .collect())
+    }
+}
+
+/// Get the ID of the album an Imgur link points to.
+fn album_id(url: &Url) -> Option<&str> {
+    let mut segments = url.path_segments()?;
+    match (segments.next(), segments.next(), segments.next()) {
+        (Some("a"), Some(id), None) | (Some("gallery"), Some(id), None) if !id.is_empty() => {
+            Some(id)
+        }
+        _ => None,
+    }
+}
+
+/// Previews of images on Reddit, e.g. `https://preview.redd.it/<id>.png?s=…`.
+struct RedditPreview;
+
+#[async_trait]
+impl HostResolver for RedditPreview {
+    fn hosts(&self) -> &'static [&'static str] {
+        &["preview.redd.it"]
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        let path = url.path();
+        !path.trim_start_matches('/').contains('/')
+            && [".jpg", ".jpeg", ".png", ".gif"]
+                .iter()
+                .any(|ext| path.ends_with(ext))
+    }
+
+    async fn resolve(&self, url: &Url) -> Result<Vec<Media>, ResolveError> {
+        // The query is signed, so it must be kept as is; Reddit only escapes
+        // it as HTML in its responses.
+        let url = url.as_str().replace("&amp;", "&");
+        Ok(vec![Media::new(url, None)])
+    }
+}
@@ -0,0 +1,249 @@
+// birbfetcher - Collect bird images with ease.
+// Copyright (C) 2020-2021 Mariell Hoversholm
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::prelude::*;
+use futures::lock::Mutex;
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+use std::env;
+use std::time::{Duration, Instant};
+
+/// The URL to request access tokens from unless configured otherwise.
+const TOKEN_URL: &str = "https://www.reddit.com/api/v1/access_token";
+
+/// How long before it expires to replace a token.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+/// How we authenticate with Reddit.
+enum Grant {
+    /// As the application itself, without any user.
+    ClientCredentials,
+
+    /// As the developer of a script application.
+    Password { username: String, password: String },
+}
+
+/// An access token along with when it expires.
+struct Token {
+    access_token: String,
+    expires_at: Instant,
+}
+
+/// An OAuth2 client for the Reddit API, caching its access token.
+pub struct OAuth {
+    client_id: String,
+    client_secret: String,
+    grant: Grant,
+    token_url: String,
+    token: Mutex<Option<Token>>,
+}
+
+impl OAuth {
+    /// Configure OAuth from the environment.
+    ///
+    /// This uses `REDDIT_CLIENT_ID` and `REDDIT_CLIENT_SECRET` for the client
+    /// credentials grant. If `REDDIT_USERNAME` and `REDDIT_PASSWORD` are also
+    /// set, the password grant is used instead. Tokens are requested from
+    /// `REDDIT_TOKEN_URL` if set.
+    ///
+    /// Returns `None` if no client ID is set, i.e. we should stay anonymous.
+    pub fn from_env() -> Option<Self> {
+        let client_id = env::var("REDDIT_CLIENT_ID").ok()?;
+        let client_secret = env::var("REDDIT_CLIENT_SECRET").unwrap_or_default();
+        let grant = match (env::var("REDDIT_USERNAME"), env::var("REDDIT_PASSWORD")) {
+            (Ok(username), Ok(password)) => {
+                info!("Authenticating with Reddit as /u/{}.", username);
+                Grant::Password { username, password }
+            }
+            _ => {
+                info!("Authenticating with Reddit as application {}.", client_id);
+                Grant::ClientCredentials
+            }
+        };
+
+        Some(Self {
+            client_id,
+            client_secret,
+            grant,
+            token_url: env::var("REDDIT_TOKEN_URL").unwrap_or_else(|_| TOKEN_URL.into()),
+            token: Mutex::new(None),
+        })
+    }
+
+    /// Get an access token, requesting a new one if the current one is about
+    /// to expire.
+    pub async fn token(&self) -> Result<String, RedditError> {
+        let mut token = self.token.lock().await;
+        match *token {
+            Some(ref token) if token.expires_at > Instant::now() + EXPIRY_MARGIN => {
+                Ok(token.access_token.clone())
+            }
+            _ => {
+                let new = self.request_token().await?;
+                let access_token = new.access_token.clone();
+                *token = Some(new);
+                Ok(access_token)
+            }
+        }
+    }
+
+    /// Forget the current access token, e.g. because it was rejected.
+    pub async fn invalidate(&self) {
+        *self.token.lock().await = None;
+    }
+
+    async fn request_token(&self) -> Result<Token, RedditError> {
+        debug!("Requesting a new Reddit access token...");
+        let form: &[(&str, &str)] = match self.grant {
+            Grant::ClientCredentials => &[("grant_type", "client_credentials")],
+            Grant::Password {
+                ref username,
+                ref password,
+            } => &[
+                ("grant_type", "password"),
+                ("username", username),
+                ("password", password),
+            ],
+        };
+        let req = crate::REQWEST_CLIENT
+            .post(&self.token_url)
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(form)
+            .send()
+            .await?;
+
+        if !req.status().is_success() {
+            return Err(RedditError::Unsuccessful(req.status()));
+        }
+
+        #[derive(Deserialize)]
+        struct TokenResponse {
+            access_token: Option<String>,
+
+            #[serde(default)]
+            expires_in: u64,
+
+            // Reddit reports some errors with a successful status code.
+            error: Option<JsonValue>,
+        }
+
+        let resp: TokenResponse = serde_json::from_str(&req.text().await?)?;
+        match (resp.access_token, resp.error) {
+            (Some(access_token), None) => Ok(Token {
+                access_token,
+                expires_at: Instant::now() + Duration::from_secs(resp.expires_in),
+            }),
+            (_, Some(error)) => Err(RedditError::Auth(error.to_string())),
+            (None, None) => Err(RedditError::Auth("no access token was given".into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use warp::http::StatusCode;
+    use warp::Filter as _;
+
+    /// Serve a token endpoint handing out `token-1`, `token-2`, ... and an API
+    /// endpoint rejecting `token-1`, returning where it is served and how many
+    /// tokens were handed out.
+    fn mock_reddit() -> (SocketAddr, Arc<AtomicUsize>) {
+        let issued = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&issued);
+        let token = warp::post()
+            .and(warp::path!("api" / "v1" / "access_token"))
+            .and(warp::header::exact("authorization", "Basic aWQ6c2VjcmV0"))
+            .and(warp::body::form())
+            .map(move |form: HashMap<String, String>| {
+                assert_eq!(form["grant_type"], "client_credentials");
+                let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                warp::reply::json(&serde_json::json!({
+                    "access_token": format!("token-{}", n),
+                    "token_type": "bearer",
+                    "expires_in": 3600,
+                }))
+            });
+        let api = warp::get()
+            .and(warp::path!("api" / "info"))
+            .and(warp::header("authorization"))
+            .map(|auth: String| match auth.as_str() {
+                "Bearer token-1" => StatusCode::UNAUTHORIZED,
+                _ => StatusCode::OK,
+            });
+
+        let (addr, server) = warp::serve(token.or(api)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (addr, issued)
+    }
+
+    fn oauth(addr: SocketAddr) -> OAuth {
+        OAuth {
+            client_id: "id".into(),
+            client_secret: "secret".into(),
+            grant: Grant::ClientCredentials,
+            token_url: format!("http://{}/api/v1/access_token", addr),
+            token: Mutex::new(None),
+        }
+    }
+
+    #[tokio::test]
+    async fn token_is_cached_until_invalidated() {
+        let (addr, issued) = mock_reddit();
+        let oauth = oauth(addr);
+
+        assert_eq!(oauth.token().await.unwrap(), "token-1");
+        assert_eq!(oauth.token().await.unwrap(), "token-1");
+        assert_eq!(issued.load(Ordering::SeqCst), 1);
+
+        oauth.invalidate().await;
+        assert_eq!(oauth.token().await.unwrap(), "token-2");
+        assert_eq!(issued.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_token_request_is_an_error() {
+        let (addr, _) = mock_reddit();
+        let mut oauth = oauth(addr);
+        oauth.client_secret = "wrong".into();
+
+        match oauth.token().await {
+            Err(RedditError::Unsuccessful(status)) => assert_eq!(status.as_u16(), 400),
+            _ => panic!("expected the token request to fail"),
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_token_is_replaced() {
+        let (addr, issued) = mock_reddit();
+        // This is the only test using the client configured from the
+        // environment.
+        env::set_var("REDDIT_CLIENT_ID", "id");
+        env::set_var("REDDIT_CLIENT_SECRET", "secret");
+        env::set_var(
+            "REDDIT_TOKEN_URL",
+            format!("http://{}/api/v1/access_token", addr),
+        );
+        env::set_var("REDDIT_API_URL", format!("http://{}", addr));
+
+        let resp = crate::reddit::get("/api/info").await.unwrap();
+        assert_eq!(resp.status().as_u16(), 200);
+        assert_eq!(issued.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,122 @@
+// birbfetcher - Collect bird images with ease.
+// Copyright (C) 2020-2021 Mariell Hoversholm
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::prelude::*;
+use futures::lock::Mutex;
+use rand::Rng as _;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use std::time::{Duration, Instant};
+
+/// The delay before the first retry of a failed request.
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The longest delay between retries of a failed request.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// The longest Reddit may have us wait, be it for a window to reset or before
+/// retrying, in case it reports something absurd.
+const MAX_WAIT: Duration = Duration::from_secs(5 * 60);
+
+/// How many requests we have left in the current window, and when it ends.
+#[derive(Default)]
+struct Window {
+    remaining: Option<f64>,
+    reset_at: Option<Instant>,
+}
+
+/// A rate limiter following the limits Reddit reports in its responses.
+///
+/// Requests are let through until Reddit says we have none left, after which
+/// they wait for the window to reset.
+#[derive(Default)]
+pub struct RateLimiter {
+    window: Mutex<Window>,
+}
+
+impl RateLimiter {
+    /// Wait until a request may be sent.
+    pub async fn acquire(&self) {
+        // Keep the lock while waiting, such that requests go out one by one
+        // once the window resets.
+        let mut window = self.window.lock().await;
+        if let (Some(remaining), Some(reset_at)) = (window.remaining, window.reset_at) {
+            let now = Instant::now();
+            if remaining < 1.0 && reset_at > now {
+                let wait = reset_at - now;
+                info!(
+                    "Out of Reddit requests, throttling for {:.1}s...",
+                    wait.as_secs_f64()
+                );
+                tokio::time::delay_for(wait).await;
+            }
+            if reset_at <= Instant::now() {
+                *window = Window::default();
+            }
+        }
+
+        // Count this request until Reddit tells us otherwise.
+        if let Some(ref mut remaining) = window.remaining {
+            *remaining -= 1.0;
+        }
+    }
+
+    /// Update the window from the `X-Ratelimit-*` headers of a response.
+    pub async fn update(&self, headers: &HeaderMap) {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse::<f64>().ok())
+                .filter(|value| value.is_finite())
+        };
+        let (remaining, reset) =
+            match (header("x-ratelimit-remaining"), header("x-ratelimit-reset")) {
+                (Some(remaining), Some(reset)) => (remaining, reset),
+                _ => return,
+            };
+
+        trace!(
+            "Reddit rate limit: {} requests left for {}s",
+            remaining,
+            reset
+        );
+        let mut window = self.window.lock().await;
+        window.remaining = Some(remaining);
+        let reset = reset.max(0.0).min(MAX_WAIT.as_secs_f64());
+        window.reset_at = Some(Instant::now() + Duration::from_secs_f64(reset));
+    }
+}
+
+/// Get how long to wait before retrying a failed request.
+///
+/// This is the `Retry-After` of the response if given, up to `MAX_WAIT`, and
+/// an exponentially growing delay with jitter otherwise, such that the retries
+/// of concurrent requests spread out.
+pub fn backoff(attempt: u32, headers: &HeaderMap) -> Duration {
+    let retry_after = headers
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .map(Duration::from_secs);
+    if let Some(retry_after) = retry_after {
+        return retry_after.min(MAX_WAIT);
+    }
+
+    let delay = BASE_BACKOFF
+        .checked_mul(2u32.saturating_pow(attempt))
+        .map_or(MAX_BACKOFF, |delay| delay.min(MAX_BACKOFF));
+    delay / 2 + delay.mul_f64(rand::thread_rng().gen_range(0.0, 0.5))
+}
@@ -0,0 +1,385 @@
+// birbfetcher - Collect bird images with ease.
+// Copyright (C) 2020-2021 Mariell Hoversholm
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::database::JobState;
+use crate::prelude::*;
+use async_trait::async_trait;
+use chrono::{TimeZone as _, Utc};
+use futures::FutureExt as _;
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use std::any::Any;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How often to check whether any job is due.
+const TICK: Duration = Duration::from_secs(1);
+
+/// How many of the latest runs to keep track of.
+const MAX_RUNS: usize = 100;
+
+/// When a job should run.
+#[derive(Clone, Debug)]
+pub enum Schedule {
+    /// Every given duration after the last run started.
+    Every(Duration),
+
+    /// Whenever the cron expression matches, e.g. `0 */10 * * * *`.
+    Cron(Box<cron::Schedule>),
+}
+
+impl Schedule {
+    /// Get when to run next after the given time, in seconds since the Unix
+    /// epoch.
+    fn next_after(&self, after: i64) -> Option<i64> {
+        match self {
+            Self::Every(interval) => Some(after + interval.as_secs() as i64),
+            Self::Cron(schedule) => schedule
+                .after(&Utc.timestamp(after, 0))
+                .next()
+                .map(|next| next.timestamp()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Schedule {
+    /// Deserialize a schedule from either a number of seconds or a cron
+    /// expression.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ScheduleVisitor;
+
+        impl<'de> Visitor<'de> for ScheduleVisitor {
+            type Value = Schedule;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("an interval in seconds or a cron expression")
+            }
+
+            fn visit_i64<E: de::Error>(self, secs: i64) -> Result<Schedule, E> {
+                match secs {
+                    secs if secs > 0 => Ok(Schedule::Every(Duration::from_secs(secs as u64))),
+                    _ => Err(E::custom("the interval must be positive")),
+                }
+            }
+
+            fn visit_str<E: de::Error>(self, expr: &str) -> Result<Schedule, E> {
+                expr.parse()
+                    .map(|schedule| Schedule::Cron(Box::new(schedule)))
+                    .map_err(|e| E::custom(format!("invalid cron expression `{}`: {}", expr, e)))
+            }
+        }
+
+        deserializer.deserialize_any(ScheduleVisitor)
+    }
+}
+
+/// A task run by the scheduler.
+#[async_trait]
+pub trait Job: Send + Sync {
+    /// Run the task, reporting how far it has come to the progress.
+    async fn run(&self, progress: &Progress) -> Result<(), JobError>;
+}
+
+/// How far a run has come.
+#[derive(Debug, Default)]
+pub struct Progress {
+    done: AtomicU64,
+    total: AtomicU64,
+}
+
+impl Progress {
+    /// Set how many steps the run has, if it is known.
+    pub fn set_total(&self, total: u64) {
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    /// Mark a step of the run as done.
+    pub fn advance(&self) {
+        self.done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn done(&self) -> u64 {
+        self.done.load(Ordering::Relaxed)
+    }
+
+    /// Get how many steps the run has, if it is known.
+    pub fn total(&self) -> Option<u64> {
+        Some(self.total.load(Ordering::Relaxed)).filter(|&total| total > 0)
+    }
+}
+
+/// A run of a job, whether on schedule or on demand.
+#[derive(Debug)]
+pub struct Run {
+    pub id: u64,
+    pub job: String,
+
+    /// When the run started, in seconds since the Unix epoch.
+    pub started_at: i64,
+
+    pub progress: Progress,
+    outcome: Mutex<Option<Outcome>>,
+}
+
+impl Run {
+    /// Get how the run ended, unless it is still running.
+    pub fn outcome(&self) -> Option<Outcome> {
+        self.outcome.lock().unwrap().clone()
+    }
+}
+
+/// How a run ended.
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    /// When the run finished, in seconds since the Unix epoch.
+    pub finished_at: i64,
+
+    /// Why the run failed, if it did.
+    pub error: Option<String>,
+}
+
+/// A scheduled job along with when it runs.
+struct Entry {
+    name: &'static str,
+
+    /// The schedule used unless one is configured for the job.
+    default_schedule: Schedule,
+
+    job: Arc<dyn Job>,
+    state: Mutex<JobState>,
+}
+
+impl Entry {
+    /// Get the schedule of the job, as currently configured.
+    fn schedule(&self) -> Schedule {
+        crate::config::current()
+            .jobs
+            .get(self.name)
+            .cloned()
+            .unwrap_or_else(|| self.default_schedule.clone())
+    }
+}
+
+/// A scheduler of named jobs.
+///
+/// When every scheduled job has last run and will run next is saved to the
+/// database, such that restarts don't make jobs run early or not at all. Jobs
+/// never run more than once at a time by the same name; a job which is due
+/// while still running is skipped until its next run.
+pub struct Scheduler {
+    db: Arc<dyn Repository>,
+    jobs: Vec<Arc<Entry>>,
+
+    /// The names of the jobs which are running.
+    running: Mutex<HashSet<String>>,
+
+    /// The latest runs, oldest first.
+    runs: Mutex<VecDeque<Arc<Run>>>,
+
+    next_run_id: AtomicU64,
+}
+
+impl Scheduler {
+    pub fn new(db: Arc<dyn Repository>) -> Self {
+        Self {
+            db,
+            jobs: vec![],
+            running: Mutex::new(HashSet::new()),
+            runs: Mutex::new(VecDeque::new()),
+            next_run_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Add a job, running on the given schedule unless one is configured for
+    /// it under `[jobs]` in the configuration.
+    pub fn add(&mut self, name: &'static str, schedule: Schedule, job: impl Job + 'static) {
+        self.jobs.push(Arc::new(Entry {
+            name,
+            default_schedule: schedule,
+            job: Arc::new(job),
+            state: Mutex::new(JobState::default()),
+        }));
+    }
+
+    /// Run the jobs whenever they are due, forever.
+    pub async fn run(self: Arc<Self>) {
+        let now = Utc::now().timestamp();
+        for entry in &self.jobs {
+            // A job whose state can't be loaded is scheduled as if it was new,
+            // rather than keeping every other job from running.
+            let mut state = match self.db.job_state(entry.name).await {
+                Ok(state) => state.unwrap_or_default(),
+                Err(e) => {
+                    error!("Could not load state of job {}: {}", entry.name, e);
+                    JobState::default()
+                }
+            };
+            // A job which has never run, or was interrupted by a restart, runs
+            // right away.
+            if state.next_run_at.is_none() || state.last_finished_at < state.last_started_at {
+                state.next_run_at = Some(now);
+            }
+            debug!(
+                "Scheduled job {} to run at {:?}.",
+                entry.name, state.next_run_at
+            );
+            *entry.state.lock().unwrap() = state;
+        }
+
+        let mut timer = async_timer::Interval::platform_new(TICK);
+        loop {
+            let now = Utc::now().timestamp();
+            for entry in &self.jobs {
+                let next_run_at = entry.state.lock().unwrap().next_run_at;
+                if next_run_at.is_none_or(|at| at > now) {
+                    continue;
+                }
+
+                entry.state.lock().unwrap().next_run_at = entry.schedule().next_after(now);
+                if let Err(SchedulerError::Running(_)) =
+                    self.start(entry.name, entry.job.clone(), Some(entry.clone()))
+                {
+                    warn!(
+                        "Job {} is still running, skipping it until its next run.",
+                        entry.name
+                    );
+                }
+            }
+            timer.as_mut().await;
+        }
+    }
+
+    /// Run a scheduled job now, regardless of its schedule.
+    pub fn trigger(self: &Arc<Self>, name: &str) -> Result<Arc<Run>, SchedulerError> {
+        let entry = self
+            .jobs
+            .iter()
+            .find(|entry| entry.name == name)
+            .ok_or_else(|| SchedulerError::NoSuchJob(name.to_owned()))?;
+        self.start(entry.name, entry.job.clone(), Some(entry.clone()))
+    }
+
+    /// Run a job once, now.
+    pub fn run_once(
+        self: &Arc<Self>,
+        name: &str,
+        job: impl Job + 'static,
+    ) -> Result<Arc<Run>, SchedulerError> {
+        self.start(name, Arc::new(job), None)
+    }
+
+    /// Get one of the latest runs by its ID.
+    pub fn find_run(&self, id: u64) -> Option<Arc<Run>> {
+        self.runs
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|run| run.id == id)
+            .cloned()
+    }
+
+    /// Start a run of the job in the background, unless it is still running.
+    ///
+    /// The state of scheduled jobs is saved as they start and finish.
+    fn start(
+        self: &Arc<Self>,
+        name: &str,
+        job: Arc<dyn Job>,
+        entry: Option<Arc<Entry>>,
+    ) -> Result<Arc<Run>, SchedulerError> {
+        if !self.running.lock().unwrap().insert(name.to_owned()) {
+            return Err(SchedulerError::Running(name.to_owned()));
+        }
+
+        let run = Arc::new(Run {
+            id: self.next_run_id.fetch_add(1, Ordering::Relaxed),
+            job: name.to_owned(),
+            started_at: Utc::now().timestamp(),
+            progress: Progress::default(),
+            outcome: Mutex::new(None),
+        });
+        {
+            let mut runs = self.runs.lock().unwrap();
+            if runs.len() >= MAX_RUNS {
+                runs.pop_front();
+            }
+            runs.push_back(run.clone());
+        }
+
+        let scheduler = self.clone();
+        let spawned = run.clone();
+        tokio::spawn(async move {
+            let run = spawned;
+            if let Some(ref entry) = entry {
+                let state = {
+                    let mut state = entry.state.lock().unwrap();
+                    state.last_started_at = Some(run.started_at);
+                    state.clone()
+                };
+                scheduler.save_state(entry.name, &state).await;
+            }
+
+            debug!("Running job {} (run {})...", run.job, run.id);
+            // A panicking job must still finish its run, or it would never
+            // run again.
+            let result = AssertUnwindSafe(job.run(&run.progress))
+                .catch_unwind()
+                .await
+                .unwrap_or_else(|panic| Err(JobError::Panicked(panic_message(&*panic))));
+            if let Err(ref e) = result {
+                error!("Job {} (run {}) failed: {}", run.job, run.id, e);
+            }
+            let outcome = Outcome {
+                finished_at: Utc::now().timestamp(),
+                error: result.err().map(|e| e.to_string()),
+            };
+
+            if let Some(ref entry) = entry {
+                let state = {
+                    let mut state = entry.state.lock().unwrap();
+                    state.last_finished_at = Some(outcome.finished_at);
+                    state.last_error = outcome.error.clone();
+                    state.clone()
+                };
+                scheduler.save_state(entry.name, &state).await;
+            }
+            *run.outcome.lock().unwrap() = Some(outcome);
+            scheduler.running.lock().unwrap().remove(&run.job);
+        });
+
+        Ok(run)
+    }
+
+    async fn save_state(&self, name: &str, state: &JobState) {
+        if let Err(e) = self.db.save_job_state(name, state).await {
+            error!("Could not save state of job {}: {}", name, e);
+        }
+    }
+}
+
+/// Get the message a panic was started with, if it has one.
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown cause".into()
+    }
+}
@@ -0,0 +1,125 @@
+// birbfetcher - Collect bird images with ease.
+// Copyright (C) 2020-2021 Mariell Hoversholm
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+mod local;
+mod s3;
+
+pub use self::local::{Layout, LocalStorage};
+pub use self::s3::S3Storage;
+
+use crate::prelude::*;
+use anyhow::{Context as _, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use std::env;
+use std::path::Path;
+use tempfile::NamedTempFile;
+
+/// A stream of chunks of a stored object.
+pub type ByteStream = BoxStream<'static, Result<Bytes, StorageError>>;
+
+/// A place to store images in.
+///
+/// Objects are addressed by a key; for images this is the uppercase hex of
+/// their SHA-256 hash.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Store the data under the given key, replacing any existing object.
+    ///
+    /// The object only ever appears whole.
+    async fn put(&self, key: &str, data: Bytes) -> Result<(), StorageError>;
+
+    /// Store the file at the path under the given key, replacing any existing
+    /// object.
+    ///
+    /// The object only ever appears whole, and the file may be moved away
+    /// from the path to store it.
+    async fn put_file(&self, key: &str, path: &Path) -> Result<(), StorageError>;
+
+    /// Create a temporary file to write an object to before storing it with
+    /// `put_file`.
+    ///
+    /// The file is deleted once dropped, unless it has been stored. It is
+    /// created wherever it can be stored from most cheaply.
+    fn temp_file(&self) -> Result<NamedTempFile, StorageError> {
+        Ok(NamedTempFile::new()?)
+    }
+
+    /// Read the entire object into memory.
+    async fn get(&self, key: &str) -> Result<Bytes, StorageError>;
+
+    /// Check whether an object exists with the given key.
+    async fn exists(&self, key: &str) -> Result<bool, StorageError>;
+
+    /// Delete the object with the given key.
+    async fn delete(&self, key: &str) -> Result<(), StorageError>;
+
+    /// Get the size of the object in bytes.
+    async fn size(&self, key: &str) -> Result<u64, StorageError>;
+
+    /// Stream the object in chunks rather than reading it all at once.
+    async fn stream(&self, key: &str) -> Result<ByteStream, StorageError>;
+
+    /// Stream the bytes from `start` up to and including `end` of the object.
+    async fn stream_range(
+        &self,
+        key: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<ByteStream, StorageError>;
+}
+
+/// Create the storage backend configured by the environment.
+///
+/// `STORAGE_BACKEND` selects the backend, being either `local` (the default)
+/// or `s3`. The local backend's layout is selected by `STORAGE_LAYOUT`, being
+/// either `flat` (the default) or `sharded`.
+pub async fn from_env() -> Result<Arc<dyn Storage>> {
+    let backend = env::var("STORAGE_BACKEND").unwrap_or_else(|_| "local".into());
+    match backend.as_str() {
+        "local" => {
+            let birb_dir = env::var("BIRB_DIRECTORY").unwrap_or_else(|_| "birbs".into());
+            let layout = match env::var("STORAGE_LAYOUT") {
+                Ok(layout) => layout
+                    .parse()
+                    .context("`STORAGE_LAYOUT` must be `flat` or `sharded`")?,
+                Err(_) => Layout::Flat,
+            };
+            let storage = Arc::new(LocalStorage::new(birb_dir, layout).await?);
+
+            // Move any files over from the other layout in the background;
+            // they can be found in either place in the meantime.
+            let migrating = storage.clone();
+            tokio::spawn(async move {
+                match migrating.migrate().await {
+                    Ok(0) => (),
+                    Ok(n) => info!("Moved {} files into the {} layout.", n, layout),
+                    Err(e) => error!("Could not migrate to the {} layout: {}", layout, e),
+                }
+            });
+
+            Ok(storage)
+        }
+        "s3" => {
+            let bucket = env::var("S3_BUCKET").context("`S3_BUCKET` must be set")?;
+            let region = env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".into());
+            let endpoint = env::var("S3_ENDPOINT").ok();
+            Ok(Arc::new(S3Storage::new(bucket, region, endpoint)?))
+        }
+        other => anyhow::bail!("unknown `STORAGE_BACKEND`: {}", other),
+    }
+}
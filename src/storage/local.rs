@@ -0,0 +1,295 @@
+// birbfetcher - Collect bird images with ease.
+// Copyright (C) 2020-2021 Mariell Hoversholm
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::{ByteStream, Storage};
+use crate::prelude::*;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::TryStreamExt as _;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use strum_macros::{Display, EnumString};
+use tempfile::NamedTempFile;
+use tokio::fs;
+use tokio::io::AsyncReadExt as _;
+use tokio_util::codec::{BytesCodec, FramedRead};
+
+/// How files are laid out within the storage directory.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Display, EnumString)]
+pub enum Layout {
+    /// Every file directly in the root, e.g. `ABCDEF…`.
+    #[strum(serialize = "flat")]
+    Flat,
+
+    /// Files in two levels of directories by their key's prefix, e.g.
+    /// `AB/CD/ABCDEF…`.
+    #[strum(serialize = "sharded")]
+    Sharded,
+}
+
+impl Layout {
+    /// The layout we may find files in which were stored before switching to
+    /// this one.
+    fn other(self) -> Self {
+        match self {
+            Self::Flat => Self::Sharded,
+            Self::Sharded => Self::Flat,
+        }
+    }
+
+    /// Get the path of the key in this layout.
+    fn path(self, root: &Path, key: &str) -> PathBuf {
+        match (self, key.get(0..2), key.get(2..4)) {
+            (Self::Sharded, Some(first), Some(second)) => root.join(first).join(second).join(key),
+            // Too short keys can't be sharded.
+            _ => root.join(key),
+        }
+    }
+}
+
+/// A storage backend keeping every object as a file in a local directory.
+pub struct LocalStorage {
+    root: PathBuf,
+    layout: Layout,
+}
+
+impl LocalStorage {
+    /// Create a new local storage in the given directory, creating it if
+    /// needed.
+    pub async fn new(root: impl Into<PathBuf>, layout: Layout) -> Result<Self, StorageError> {
+        let root = root.into();
+        if fs::metadata(&root).await.is_err() {
+            // Metadata should only fail if there is no such dir.
+            fs::create_dir_all(&root).await?;
+        }
+
+        Ok(Self { root, layout })
+    }
+
+    /// Run an operation on the file of the key, wherever it is.
+    ///
+    /// Files are looked for in the configured layout first, then in the other
+    /// one in case they have not been migrated yet.
+    async fn find<T, F, Fut>(&self, key: &str, op: F) -> Result<T, StorageError>
+    where
+        F: Fn(PathBuf) -> Fut,
+        Fut: Future<Output = std::io::Result<T>>,
+    {
+        let paths = [
+            self.layout.path(&self.root, key),
+            self.layout.other().path(&self.root, key),
+            // A migration may have moved the file between our two lookups.
+            self.layout.path(&self.root, key),
+        ];
+        for path in paths.iter() {
+            match op(path.clone()).await {
+                Ok(t) => return Ok(t),
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Err(StorageError::NotFound)
+    }
+
+    /// Move a file into its place in the configured layout.
+    async fn relocate(&self, from: &Path, key: &str) -> Result<(), StorageError> {
+        let to = self.layout.path(&self.root, key);
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        // Renames are atomic, so readers will find the file in either place.
+        fs::rename(from, to).await?;
+        Ok(())
+    }
+
+    /// Move every file stored in the other layout into the configured one.
+    ///
+    /// This is safe to run while the storage is in use, as lookups check both
+    /// layouts. Files starting with a dot are still being written, and are left
+    /// alone, as is anything else in a shard not named by its key's prefix.
+    /// Returns how many files were moved.
+    pub async fn migrate(&self) -> Result<usize, StorageError> {
+        let mut moved = 0;
+        match self.layout {
+            Layout::Sharded => {
+                let mut entries = fs::read_dir(&self.root).await?;
+                while let Some(entry) = entries.next_entry().await? {
+                    if !entry.file_type().await?.is_file() {
+                        continue;
+                    }
+                    let key = entry.file_name();
+                    let key = match key.to_str() {
+                        Some(key) if !key.starts_with('.') => key,
+                        _ => continue,
+                    };
+
+                    self.relocate(&entry.path(), key).await?;
+                    moved += 1;
+                }
+            }
+            Layout::Flat => {
+                let mut firsts = fs::read_dir(&self.root).await?;
+                while let Some(first) = firsts.next_entry().await? {
+                    // Other directories, such as `lost+found`, aren't ours.
+                    let first_name = match shard_name(&first).await? {
+                        Some(name) => name,
+                        None => continue,
+                    };
+                    let mut seconds = fs::read_dir(first.path()).await?;
+                    while let Some(second) = seconds.next_entry().await? {
+                        let prefix = match shard_name(&second).await? {
+                            Some(name) => first_name.clone() + &name,
+                            None => continue,
+                        };
+                        let mut files = fs::read_dir(second.path()).await?;
+                        while let Some(file) = files.next_entry().await? {
+                            let key = file.file_name();
+                            let key = match key.to_str() {
+                                Some(key) if key.starts_with(&prefix) => key,
+                                _ => continue,
+                            };
+
+                            self.relocate(&file.path(), key).await?;
+                            moved += 1;
+                        }
+                        // Only succeeds if the shard is now empty, which is fine.
+                        let _ = fs::remove_dir(second.path()).await;
+                    }
+                    let _ = fs::remove_dir(first.path()).await;
+                }
+            }
+        }
+
+        Ok(moved)
+    }
+}
+
+/// Get the name of the entry if it is a directory of a sharded layout, i.e.
+/// is named by two hexadecimal digits.
+async fn shard_name(entry: &fs::DirEntry) -> Result<Option<String>, StorageError> {
+    if !entry.file_type().await?.is_dir() {
+        return Ok(None);
+    }
+    Ok(entry
+        .file_name()
+        .to_str()
+        .filter(|name| name.len() == 2 && name.bytes().all(|b| b.is_ascii_hexdigit()))
+        .map(String::from))
+}
+
+/// Get a path next to where the key is stored to write its file to, before
+/// moving it into place.
+///
+/// The name starts with a dot, such that it is never taken for a key itself,
+/// and is unique, as other processes may write the same key at once.
+fn part_path(path: &Path, key: &str) -> PathBuf {
+    path.with_file_name(format!(".{}.{:08x}.part", key, rand::random::<u32>()))
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, data: Bytes) -> Result<(), StorageError> {
+        let path = self.layout.path(&self.root, key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        // Readers must never find a partially written file.
+        let part = part_path(&path, key);
+        fs::write(&part, &data).await?;
+        fs::rename(&part, &path).await?;
+        Ok(())
+    }
+
+    async fn put_file(&self, key: &str, path: &Path) -> Result<(), StorageError> {
+        let to = self.layout.path(&self.root, key);
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        if fs::rename(path, &to).await.is_ok() {
+            return Ok(());
+        }
+
+        // Files can't be renamed across filesystems, so copy it next to its
+        // place first.
+        let part = part_path(&to, key);
+        fs::copy(path, &part).await?;
+        fs::rename(&part, &to).await?;
+        Ok(())
+    }
+
+    fn temp_file(&self) -> Result<NamedTempFile, StorageError> {
+        // Files in the root can be renamed into place, rather than copied. The
+        // name starts with a dot, such that it is never taken for a key.
+        let file = tempfile::Builder::new()
+            .prefix(".")
+            .suffix(".part")
+            .tempfile_in(&self.root)?;
+        // Temporary files are only accessible by us, unlike the files we store.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt as _;
+            std::fs::set_permissions(file.path(), std::fs::Permissions::from_mode(0o644))?;
+        }
+        Ok(file)
+    }
+
+    async fn get(&self, key: &str) -> Result<Bytes, StorageError> {
+        self.find(key, fs::read).await.map(Bytes::from)
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, StorageError> {
+        match self.find(key, fs::metadata).await {
+            Ok(_) => Ok(true),
+            Err(StorageError::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.find(key, fs::remove_file).await
+    }
+
+    async fn size(&self, key: &str) -> Result<u64, StorageError> {
+        Ok(self.find(key, fs::metadata).await?.len())
+    }
+
+    async fn stream(&self, key: &str) -> Result<ByteStream, StorageError> {
+        let file = self.find(key, fs::File::open).await?;
+        Ok(Box::pin(
+            FramedRead::new(file, BytesCodec::new())
+                .map_ok(|b| b.freeze())
+                .map_err(StorageError::from),
+        ))
+    }
+
+    async fn stream_range(
+        &self,
+        key: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<ByteStream, StorageError> {
+        let mut file = self.find(key, fs::File::open).await?;
+        file.seek(SeekFrom::Start(start)).await?;
+        Ok(Box::pin(
+            FramedRead::new(file.take(end - start + 1), BytesCodec::new())
+                .map_ok(|b| b.freeze())
+                .map_err(StorageError::from),
+        ))
+    }
+}
@@ -0,0 +1,199 @@
+// birbfetcher - Collect bird images with ease.
+// Copyright (C) 2020-2021 Mariell Hoversholm
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::{ByteStream, Storage};
+use crate::prelude::*;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::TryStreamExt as _;
+use rusoto_core::{Region, RusotoError};
+use rusoto_s3::{
+    DeleteObjectRequest, GetObjectError, GetObjectRequest, HeadObjectRequest, PutObjectRequest,
+    S3Client, S3 as _,
+};
+use std::path::Path;
+use tokio_util::codec::{BytesCodec, FramedRead};
+
+/// A storage backend keeping every object in an S3-compatible bucket.
+///
+/// Credentials are read the same way as the AWS CLI does, i.e. from the
+/// `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` env vars or the usual
+/// profile files.
+pub struct S3Storage {
+    client: S3Client,
+    bucket: String,
+}
+
+impl S3Storage {
+    /// Create a new S3 storage.
+    ///
+    /// With an endpoint given, that is used in place of AWS, e.g. for a local
+    /// MinIO instance.
+    pub fn new(
+        bucket: String,
+        region: String,
+        endpoint: Option<String>,
+    ) -> Result<Self, StorageError> {
+        let region = match endpoint {
+            Some(endpoint) => Region::Custom {
+                name: region,
+                endpoint,
+            },
+            None => region
+                .parse()
+                .map_err(|e| StorageError::S3(format!("{}", e)))?,
+        };
+
+        Ok(Self {
+            client: S3Client::new(region),
+            bucket,
+        })
+    }
+
+    /// Stream an object, or only the given HTTP range of it.
+    async fn get_object(
+        &self,
+        key: &str,
+        range: Option<String>,
+    ) -> Result<ByteStream, StorageError> {
+        let object = self
+            .client
+            .get_object(GetObjectRequest {
+                bucket: self.bucket.clone(),
+                key: key.to_owned(),
+                range,
+                ..Default::default()
+            })
+            .await
+            .map_err(get_error)?;
+        match object.body {
+            Some(body) => Ok(Box::pin(body.map_err(StorageError::from))),
+            None => Ok(Box::pin(futures::stream::empty())),
+        }
+    }
+}
+
+/// Convert any Rusoto error into a `StorageError`.
+fn s3_error<E: std::error::Error + 'static>(err: RusotoError<E>) -> StorageError {
+    match err {
+        // HEAD requests have no body to carry an error code in.
+        RusotoError::Unknown(ref resp) if resp.status.as_u16() == 404 => StorageError::NotFound,
+        err => StorageError::S3(err.to_string()),
+    }
+}
+
+/// Convert a Rusoto error from fetching an object into a `StorageError`.
+fn get_error(err: RusotoError<GetObjectError>) -> StorageError {
+    match err {
+        RusotoError::Service(GetObjectError::NoSuchKey(_)) => StorageError::NotFound,
+        err => s3_error(err),
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, key: &str, data: Bytes) -> Result<(), StorageError> {
+        self.client
+            .put_object(PutObjectRequest {
+                bucket: self.bucket.clone(),
+                key: key.to_owned(),
+                content_length: Some(data.len() as i64),
+                body: Some(data.to_vec().into()),
+                ..Default::default()
+            })
+            .await
+            .map_err(s3_error)?;
+        Ok(())
+    }
+
+    async fn put_file(&self, key: &str, path: &Path) -> Result<(), StorageError> {
+        // Objects only appear once they are uploaded whole anyway.
+        let file = tokio::fs::File::open(path).await?;
+        let len = file.metadata().await?.len();
+        let body = FramedRead::new(file, BytesCodec::new()).map_ok(|b| b.freeze());
+        self.client
+            .put_object(PutObjectRequest {
+                bucket: self.bucket.clone(),
+                key: key.to_owned(),
+                content_length: Some(len as i64),
+                body: Some(rusoto_core::ByteStream::new_with_size(body, len as usize)),
+                ..Default::default()
+            })
+            .await
+            .map_err(s3_error)?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Bytes, StorageError> {
+        let chunks: Vec<Bytes> = self.stream(key).await?.try_collect().await?;
+        Ok(chunks.concat().into())
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, StorageError> {
+        let head = self
+            .client
+            .head_object(HeadObjectRequest {
+                bucket: self.bucket.clone(),
+                key: key.to_owned(),
+                ..Default::default()
+            })
+            .await;
+        match head.map_err(s3_error) {
+            Ok(_) => Ok(true),
+            Err(StorageError::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.client
+            .delete_object(DeleteObjectRequest {
+                bucket: self.bucket.clone(),
+                key: key.to_owned(),
+                ..Default::default()
+            })
+            .await
+            .map_err(s3_error)?;
+        Ok(())
+    }
+
+    async fn size(&self, key: &str) -> Result<u64, StorageError> {
+        let head = self
+            .client
+            .head_object(HeadObjectRequest {
+                bucket: self.bucket.clone(),
+                key: key.to_owned(),
+                ..Default::default()
+            })
+            .await
+            .map_err(s3_error)?;
+        Ok(head.content_length.unwrap_or_default() as u64)
+    }
+
+    async fn stream(&self, key: &str) -> Result<ByteStream, StorageError> {
+        self.get_object(key, None).await
+    }
+
+    async fn stream_range(
+        &self,
+        key: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<ByteStream, StorageError> {
+        self.get_object(key, Some(format!("bytes={}-{}", start, end)))
+            .await
+    }
+}
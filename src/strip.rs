@@ -0,0 +1,443 @@
+// birbfetcher - Collect bird images with ease.
+// Copyright (C) 2020-2021 Mariell Hoversholm
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::prelude::*;
+use bytes::Bytes;
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageFormat};
+use once_cell::sync::Lazy;
+use std::convert::TryInto as _;
+use std::env;
+use std::io::Cursor;
+use std::path::Path;
+
+/// Whether to store images exactly as they were fetched, set by
+/// `KEEP_ORIGINALS`.
+pub static KEEP_ORIGINALS: Lazy<bool> =
+    Lazy::new(|| env::var("KEEP_ORIGINALS").is_ok_and(|k| k == "true"));
+
+/// The quality to encode rotated JPEG images with, from 0 to 100.
+const QUALITY: u8 = 90;
+
+/// The JPEG markers of segments which only hold metadata: EXIF and XMP
+/// (`APP1`), IPTC (`APP13`) and comments (`COM`).
+const JPEG_METADATA: &[u8] = &[0xE1, 0xED, 0xFE];
+
+/// The PNG chunks which only hold metadata.
+const PNG_METADATA: &[&[u8]] = &[b"eXIf", b"tEXt", b"zTXt", b"iTXt", b"tIME"];
+
+/// The WebP chunks which only hold metadata.
+const WEBP_METADATA: &[&[u8]] = &[b"EXIF", b"XMP "];
+
+/// An image without its metadata.
+#[derive(Debug, Default)]
+struct Stripped {
+    data: Vec<u8>,
+
+    /// The EXIF orientation of the image, if it had any.
+    orientation: Option<u16>,
+
+    animated: bool,
+}
+
+/// Strip the image in the file of its metadata, such as where and with what it
+/// was taken, and rotate it as its EXIF orientation says.
+///
+/// Metadata is removed without decoding the image where possible; only images
+/// which have to be rotated are encoded again. Files in formats without
+/// metadata we know of aren't read, and files without any metadata aren't
+/// written.
+pub async fn strip_metadata(content_type: &str, path: &Path) -> Result<(), StripError> {
+    let format = match ImageFormat::from_mime_type(content_type) {
+        Some(format @ (ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP)) => format,
+        _ => return Ok(()),
+    };
+    let data = Bytes::from(tokio::fs::read(path).await?);
+    let original = data.clone();
+    let stripped = tokio::task::spawn_blocking(move || strip(&data, format))
+        .await
+        .map_err(|e| StripError::Join(e.to_string()))??;
+    if stripped != original {
+        tokio::fs::write(path, &stripped).await?;
+    }
+    Ok(())
+}
+
+fn strip(data: &[u8], format: ImageFormat) -> Result<Bytes, StripError> {
+    let stripped = match format {
+        ImageFormat::Jpeg => strip_jpeg(data),
+        ImageFormat::Png => strip_png(data),
+        _ => strip_webp(data),
+    }
+    .ok_or(StripError::Malformed)?;
+
+    // Rotating animated images would only keep their first frame.
+    let orientation = match stripped.orientation {
+        Some(orientation @ 2..=8) if !stripped.animated => orientation,
+        _ => return Ok(stripped.data.into()),
+    };
+
+    trace!("Rotating image with EXIF orientation {}", orientation);
+    let image = image::load_from_memory_with_format(&stripped.data, format)?;
+    let image = orient(image, orientation);
+    let mut data = Vec::new();
+    match format {
+        ImageFormat::Jpeg => {
+            JpegEncoder::new_with_quality(&mut data, QUALITY).encode_image(&image.to_rgb8())?
+        }
+        ImageFormat::Png => image.write_to(&mut Cursor::new(&mut data), ImageFormat::Png)?,
+        _ => {
+            let image = image.to_rgba8();
+            let encoded =
+                webp::Encoder::from_rgba(&image, image.width(), image.height()).encode_lossless();
+            data.extend_from_slice(&encoded);
+        }
+    }
+    Ok(data.into())
+}
+
+/// Apply an EXIF orientation to the image, such that it is displayed upright
+/// without it.
+fn orient(image: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Strip the metadata segments of a JPEG image, up until its scan.
+fn strip_jpeg(data: &[u8]) -> Option<Stripped> {
+    let mut stripped = Stripped::default();
+    stripped.data.reserve(data.len());
+    stripped
+        .data
+        .extend_from_slice(data.get(..2).filter(|soi| soi == b"\xFF\xD8")?);
+
+    let mut pos = 2;
+    loop {
+        if *data.get(pos)? != 0xFF {
+            return None;
+        }
+        let marker = *data.get(pos + 1)?;
+        match marker {
+            // Markers may be padded by any amount of fill bytes.
+            0xFF => {
+                pos += 1;
+                continue;
+            }
+            // The scan and everything after it is image data, and markers
+            // without segments hold nothing at all.
+            0xDA | 0xD9 => {
+                stripped.data.extend_from_slice(&data[pos..]);
+                return Some(stripped);
+            }
+            0x01 | 0xD0..=0xD7 => {
+                stripped.data.extend_from_slice(&data[pos..pos + 2]);
+                pos += 2;
+                continue;
+            }
+            _ => (),
+        }
+
+        // The length of a segment includes itself, but not the marker.
+        let len = u16::from_be_bytes(data.get(pos + 2..pos + 4)?.try_into().ok()?) as usize;
+        let segment = data.get(pos..pos + 2 + len.max(2))?;
+        if marker == 0xE1 {
+            if let Some(exif) = segment[4..].strip_prefix(b"Exif\0\0") {
+                stripped.orientation = stripped.orientation.or_else(|| exif_orientation(exif));
+            }
+        }
+        if !JPEG_METADATA.contains(&marker) {
+            stripped.data.extend_from_slice(segment);
+        }
+        pos += segment.len();
+    }
+}
+
+/// Strip the metadata chunks of a PNG image.
+fn strip_png(data: &[u8]) -> Option<Stripped> {
+    let mut stripped = Stripped::default();
+    stripped.data.reserve(data.len());
+    stripped.data.extend_from_slice(data.get(..8)?);
+
+    let mut pos = 8;
+    while pos < data.len() {
+        // Every chunk is its length, type, data and CRC.
+        let len = u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        let chunk = data.get(pos..pos + 12 + len)?;
+        let ty = &chunk[4..8];
+        match ty {
+            b"eXIf" => stripped.orientation = exif_orientation(&chunk[8..8 + len]),
+            b"acTL" => stripped.animated = true,
+            _ => (),
+        }
+        if !PNG_METADATA.contains(&ty) {
+            stripped.data.extend_from_slice(chunk);
+        }
+        pos += chunk.len();
+    }
+    Some(stripped)
+}
+
+/// Strip the metadata chunks of a WebP image.
+fn strip_webp(data: &[u8]) -> Option<Stripped> {
+    let mut stripped = Stripped::default();
+    stripped.data.reserve(data.len());
+    // The size of the RIFF container is filled in once it is known.
+    stripped.data.extend_from_slice(data.get(..12)?);
+
+    let mut pos = 12;
+    while pos < data.len() {
+        let len = u32::from_le_bytes(data.get(pos + 4..pos + 8)?.try_into().ok()?) as usize;
+        data.get(pos + 8..pos + 8 + len)?;
+        // Chunks are padded to an even length, though not always at the end.
+        let chunk = &data[pos..(pos + 8 + len + len % 2).min(data.len())];
+        let fourcc = &chunk[..4];
+        match fourcc {
+            b"EXIF" => {
+                let exif = &chunk[8..8 + len];
+                let exif = exif.strip_prefix(b"Exif\0\0").unwrap_or(exif);
+                stripped.orientation = exif_orientation(exif);
+            }
+            b"ANIM" => stripped.animated = true,
+            _ => (),
+        }
+        if !WEBP_METADATA.contains(&fourcc) {
+            stripped.data.extend_from_slice(chunk);
+        }
+        pos += chunk.len();
+    }
+
+    // The extended header says which metadata chunks there are.
+    if stripped.data.get(12..16) == Some(b"VP8X") {
+        *stripped.data.get_mut(20)? &= !0b1100;
+    }
+    let size = (stripped.data.len() - 8) as u32;
+    stripped.data[4..8].copy_from_slice(&size.to_le_bytes());
+    Some(stripped)
+}
+
+/// Read the orientation from the first IFD of EXIF data.
+fn exif_orientation(exif: &[u8]) -> Option<u16> {
+    let little_endian = match exif.get(..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let u16_at = |pos: usize| -> Option<u16> {
+        let bytes = exif.get(pos..pos + 2)?.try_into().ok()?;
+        Some(match little_endian {
+            true => u16::from_le_bytes(bytes),
+            false => u16::from_be_bytes(bytes),
+        })
+    };
+    let u32_at = |pos: usize| -> Option<u32> {
+        let bytes = exif.get(pos..pos + 4)?.try_into().ok()?;
+        Some(match little_endian {
+            true => u32::from_le_bytes(bytes),
+            false => u32::from_be_bytes(bytes),
+        })
+    };
+
+    // Every entry is its tag, type, count and value.
+    let ifd = u32_at(4)? as usize;
+    (0..u16_at(ifd)? as usize)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&entry| u16_at(entry) == Some(0x0112))
+        .and_then(|entry| u16_at(entry + 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageOutputFormat, Rgb, RgbImage};
+
+    /// EXIF data with only the given orientation in it.
+    fn exif(orientation: u16) -> Vec<u8> {
+        let mut exif = b"II*\0\x08\0\0\0\x01\0".to_vec();
+        exif.extend_from_slice(&[0x12, 0x01, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00]);
+        exif.extend_from_slice(&orientation.to_le_bytes());
+        exif.extend_from_slice(&[0; 6]);
+        exif
+    }
+
+    fn jpeg_segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, marker];
+        segment.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    /// A PNG chunk, with a CRC we never check.
+    fn png_chunk(ty: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(ty);
+        chunk.extend_from_slice(data);
+        chunk.extend_from_slice(&[0; 4]);
+        chunk
+    }
+
+    fn webp_chunk(fourcc: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = fourcc.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn webp(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let chunks = chunks.concat();
+        let mut data = b"RIFF".to_vec();
+        data.extend_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+        data.extend_from_slice(b"WEBP");
+        data.extend_from_slice(&chunks);
+        data
+    }
+
+    const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1A\n";
+
+    #[test]
+    fn jpeg_metadata_segments_are_dropped() {
+        let soi = vec![0xFF, 0xD8];
+        let app0 = jpeg_segment(0xE0, b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0");
+        let app1 = jpeg_segment(0xE1, &[&b"Exif\0\0"[..], &exif(6)].concat());
+        let app2 = jpeg_segment(0xE2, b"ICC_PROFILE\0\x01\x01");
+        let app13 = jpeg_segment(0xED, b"Photoshop 3.0\0");
+        let com = jpeg_segment(0xFE, b"taken at home");
+        let dqt = jpeg_segment(0xDB, &[0; 65]);
+        let scan = [
+            &jpeg_segment(0xDA, &[0; 10])[..],
+            b"\x12\x34\xFF\x00",
+            b"\xFF\xD9",
+        ]
+        .concat();
+        let data = [&soi[..], &app0, &app1, &app2, &app13, &com, &dqt, &scan].concat();
+
+        let stripped = strip_jpeg(&data).unwrap();
+        assert_eq!(stripped.data, [soi, app0, app2, dqt, scan].concat());
+        assert_eq!(stripped.orientation, Some(6));
+        assert!(!stripped.animated);
+    }
+
+    #[test]
+    fn png_metadata_chunks_are_dropped() {
+        let ihdr = png_chunk(b"IHDR", &[0; 13]);
+        let exif = png_chunk(b"eXIf", &exif(6));
+        let text = png_chunk(b"tEXt", b"Comment\0taken at home");
+        let idat = png_chunk(b"IDAT", &[0; 16]);
+        let iend = png_chunk(b"IEND", &[]);
+        let data = [PNG_SIGNATURE, &ihdr, &exif, &text, &idat, &iend].concat();
+
+        let stripped = strip_png(&data).unwrap();
+        assert_eq!(stripped.data, [PNG_SIGNATURE, &ihdr, &idat, &iend].concat());
+        assert_eq!(stripped.orientation, Some(6));
+        assert!(!stripped.animated);
+    }
+
+    #[test]
+    fn animated_png_is_not_rotated() {
+        let ihdr = png_chunk(b"IHDR", &[0; 13]);
+        let actl = png_chunk(b"acTL", &[0, 0, 0, 2, 0, 0, 0, 0]);
+        let exif = png_chunk(b"eXIf", &exif(6));
+        let idat = png_chunk(b"IDAT", &[0; 16]);
+        let iend = png_chunk(b"IEND", &[]);
+        let data = [PNG_SIGNATURE, &ihdr, &actl, &exif, &idat, &iend].concat();
+
+        let stripped = strip_png(&data).unwrap();
+        assert!(stripped.animated);
+        // The image data is made up, so this would fail if it was decoded.
+        let expected = [PNG_SIGNATURE, &ihdr, &actl, &idat, &iend].concat();
+        assert_eq!(strip(&data, ImageFormat::Png).unwrap(), expected);
+    }
+
+    #[test]
+    fn png_is_rotated_by_its_orientation() {
+        let mut image = RgbImage::new(2, 1);
+        image.put_pixel(0, 0, Rgb([255, 0, 0]));
+        image.put_pixel(1, 0, Rgb([0, 0, 255]));
+        let mut data = Vec::new();
+        DynamicImage::ImageRgb8(image)
+            .write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png)
+            .unwrap();
+        // The IHDR chunk always comes first, and is 25 bytes long.
+        let at = PNG_SIGNATURE.len() + 25;
+        data.splice(at..at, png_chunk(b"eXIf", &exif(6)));
+
+        let stripped = strip(&data, ImageFormat::Png).unwrap();
+        let image = image::load_from_memory_with_format(&stripped, ImageFormat::Png)
+            .unwrap()
+            .to_rgb8();
+        assert_eq!(image.dimensions(), (1, 2));
+        assert_eq!(image.get_pixel(0, 0), &Rgb([255, 0, 0]));
+        assert_eq!(image.get_pixel(0, 1), &Rgb([0, 0, 255]));
+    }
+
+    #[test]
+    fn webp_metadata_chunks_are_dropped() {
+        // The flags of 0x3C are for an ICC profile, alpha, EXIF and XMP.
+        let vp8x = |flags| webp_chunk(b"VP8X", &[flags, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let iccp = webp_chunk(b"ICCP", b"icc");
+        let vp8l = webp_chunk(b"VP8L", &[0x2F, 0, 0, 0, 0]);
+        let exif = webp_chunk(b"EXIF", &[&b"Exif\0\0"[..], &exif(6)].concat());
+        let xmp = webp_chunk(b"XMP ", b"<x:xmpmeta/>!");
+        let data = webp(&[vp8x(0x3C), iccp.clone(), vp8l.clone(), exif, xmp]);
+
+        let stripped = strip_webp(&data).unwrap();
+        assert_eq!(stripped.data, webp(&[vp8x(0x30), iccp, vp8l]));
+        assert_eq!(stripped.orientation, Some(6));
+        assert!(!stripped.animated);
+    }
+
+    #[test]
+    fn truncated_images_are_malformed() {
+        let cases = [
+            (
+                ImageFormat::Jpeg,
+                vec![0xFF, 0xD8],
+                jpeg_segment(0xE1, &[&b"Exif\0\0"[..], &exif(6)].concat()),
+            ),
+            (
+                ImageFormat::Png,
+                [PNG_SIGNATURE, &png_chunk(b"IHDR", &[0; 13])].concat(),
+                png_chunk(b"eXIf", &exif(6)),
+            ),
+            (
+                ImageFormat::WebP,
+                webp(&[webp_chunk(b"VP8X", &[0x08, 0, 0, 0, 0, 0, 0, 0, 0, 0])]),
+                webp_chunk(b"EXIF", &exif(6)),
+            ),
+        ];
+        for (format, head, metadata) in &cases {
+            assert!(matches!(strip(&[], *format), Err(StripError::Malformed)));
+            // Cut the image off anywhere within its metadata.
+            let data = [&head[..], metadata].concat();
+            for len in head.len() + 1..data.len() {
+                match strip(&data[..len], *format) {
+                    Err(StripError::Malformed) => (),
+                    other => panic!("{:?} cut at {}: {:?}", format, len, other),
+                }
+            }
+        }
+    }
+}
@@ -14,23 +14,59 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::config::Subreddit;
+use crate::database::NewImage;
+use crate::download::Download;
+use crate::phash::{Mode, MODE};
 use crate::prelude::*;
 use crate::reddit::*;
+use crate::scheduler::Progress;
+use crate::storage::Storage;
+use crate::strip::KEEP_ORIGINALS;
 use chrono::{TimeZone as _, Utc};
-use sha2::Digest as _;
-use std::path::PathBuf;
+use futures::lock::Mutex;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
 use std::time::Instant;
 
-pub async fn fetch_posts(db: &MySqlPool, birb_dir: &PathBuf, subreddits: &[String]) {
-    let mut posts = Vec::with_capacity(subreddits.len() * 100);
+/// A lock for every file being stored right now, by their hashes.
+static STORING: Lazy<Mutex<HashMap<String, Arc<Mutex<()>>>>> = Lazy::new(Default::default);
+
+/// The listings walked to backfill a subreddit, best posts first.
+const BACKFILL_LISTINGS: &[PostType] = &[
+    PostType::Top(TimeWindow::All),
+    PostType::Top(TimeWindow::Year),
+];
+
+/// Fetch and process the posts of the subreddits' configured listings, up to
+/// the given amount of pages deep.
+pub async fn fetch_posts(
+    db: &dyn Repository,
+    storage: &dyn Storage,
+    subreddits: &[Subreddit],
+    pages: usize,
+    progress: &Progress,
+) {
+    let mut posts = Vec::with_capacity(subreddits.len() * pages * 200);
     for sub in subreddits {
-        match request_posts(sub, PostType::Hot).await {
-            Ok(v) => posts.extend(v),
-            Err(e) => error!("Could not fetch any posts for {}/hot: {}", sub, e),
-        }
-        match request_posts(sub, PostType::New).await {
-            Ok(v) => posts.extend(v),
-            Err(e) => error!("Could not fetch any posts for {}/new: {}", sub, e),
+        let sub_name = &sub.name;
+        for &ty in &sub.listings {
+            let mut after = None;
+            for _ in 0..pages {
+                match request_posts(sub_name, ty, after.as_deref()).await {
+                    Ok(listing) => {
+                        posts.extend(listing.posts.into_iter().map(|post| (sub, post)));
+                        after = listing.after;
+                    }
+                    Err(e) => {
+                        error!("Could not fetch any posts for {}/{}: {}", sub_name, ty, e);
+                        break;
+                    }
+                }
+                if after.is_none() {
+                    break;
+                }
+            }
         }
     }
 
@@ -39,11 +75,17 @@ pub async fn fetch_posts(db: &MySqlPool, birb_dir: &PathBuf, subreddits: &[Strin
         posts.len()
     );
     let start = Instant::now();
-    for post in posts.iter().filter(|p| p.is_safe()) {
-        match process_post(db, birb_dir, post).await {
+    let posts = posts
+        .iter()
+        .filter(|(sub, p)| p.is_safe() && sub.accepts(p))
+        .collect::<Vec<_>>();
+    progress.set_total(posts.len() as u64);
+    for (sub, post) in posts {
+        match process_post(db, storage, sub, post).await {
             Ok(()) => (),
             Err(e) => warn!("Error on processing post ({:?}): {}", post, e),
         }
+        progress.advance();
     }
     let elapsed = start.elapsed();
     info!(
@@ -52,53 +94,205 @@ pub async fn fetch_posts(db: &MySqlPool, birb_dir: &PathBuf, subreddits: &[Strin
     );
 }
 
+/// Walk the entire history of the subreddit's top listings, processing every
+/// post on the way.
+///
+/// How far we have come is saved after every page, such that this continues
+/// where it left off when run again. Finished listings are not walked again.
+pub async fn backfill(db: &dyn Repository, storage: &dyn Storage, subreddit: &Subreddit) {
+    for &ty in BACKFILL_LISTINGS {
+        if let Err(e) = backfill_listing(db, storage, subreddit, ty).await {
+            error!("Could not backfill {}/{}: {}", subreddit.name, ty, e);
+        }
+    }
+}
+
+async fn backfill_listing(
+    db: &dyn Repository,
+    storage: &dyn Storage,
+    sub: &Subreddit,
+    ty: PostType,
+) -> Result<(), BackfillError> {
+    let subreddit = &sub.name;
+    let listing = ty.to_string();
+    let mut backfill = db.backfill(subreddit, &listing).await?.unwrap_or_default();
+    if backfill.finished {
+        return Ok(());
+    }
+
+    info!("Backfilling {}/{}...", subreddit, ty);
+    let mut processed = 0;
+    loop {
+        let page = request_posts(subreddit, ty, backfill.next_page.as_deref()).await?;
+        for post in page.posts.iter().filter(|p| p.is_safe() && sub.accepts(p)) {
+            match process_post(db, storage, sub, post).await {
+                Ok(()) => processed += 1,
+                Err(ProcessingError::Duplicate) | Err(ProcessingError::NearDuplicate { .. }) => (),
+                Err(e) => warn!("Error on processing post ({:?}): {}", post, e),
+            }
+        }
+
+        backfill.finished = page.after.is_none();
+        backfill.next_page = page.after;
+        db.save_backfill(subreddit, &listing, &backfill).await?;
+        if backfill.finished {
+            break;
+        }
+    }
+
+    info!(
+        "Finished backfilling {}/{} with {} new images.",
+        subreddit, ty, processed
+    );
+    Ok(())
+}
+
+/// Fetch and store every file of the post.
+///
+/// Every image of a gallery is attempted even if others fail, in which case
+/// the first error is returned.
 async fn process_post(
-    db: &MySqlPool,
-    birb_dir: &PathBuf,
+    db: &dyn Repository,
+    storage: &dyn Storage,
+    sub: &Subreddit,
     post: &RedditPost,
 ) -> Result<(), ProcessingError> {
-    let image = crate::REQWEST_CLIENT.get(&post.url).send().await?;
-    if !image.status().is_success() {
-        return Err(ProcessingError::Unsuccessful(image.status()));
+    let mut result = Ok(());
+    for media in post.resolve_media().await? {
+        let processed = process_media(db, storage, sub, post, &media).await;
+        if result.is_ok() {
+            result = processed;
+        }
     }
+    result
+}
 
-    // I'm not particularly interested in *what* went wrong.
-    let content_type = image
-        .headers()
-        .get(reqwest::header::CONTENT_TYPE)
-        .ok_or(ProcessingError::InvalidContentType)?;
-    let content_type = content_type
-        .to_str()
-        .map_err(|_| ProcessingError::InvalidContentType)?
-        .to_owned();
+async fn process_media(
+    db: &dyn Repository,
+    storage: &dyn Storage,
+    sub: &Subreddit,
+    post: &RedditPost,
+    media: &Media,
+) -> Result<(), ProcessingError> {
+    let req = crate::REQWEST_CLIENT.get(&media.url);
+    let download = crate::download::download(req, storage.temp_file()?).await?;
+    let hash_hex = hex::encode_upper(&download.hash);
 
-    let body = image.bytes().await?;
+    // Jobs run at the same time and may come across the same file; only one
+    // of them may store it.
+    let lock = STORING
+        .lock()
+        .await
+        .entry(hash_hex.clone())
+        .or_default()
+        .clone();
+    let result = {
+        let _storing = lock.lock().await;
+        store_media(db, storage, sub, post, media, download, &hash_hex).await
+    };
 
-    let hash = crate::utils::sha256(|h| h.update(&body));
-    let hash_hex = hex::encode_upper(&hash);
-    let path = birb_dir.join(&hash_hex);
+    // Nobody else is storing the file if only the map holds the lock.
+    let mut storing = STORING.lock().await;
+    if Arc::strong_count(&lock) <= 2 {
+        storing.remove(&hash_hex);
+    }
+    result
+}
 
-    if path.metadata().is_ok() {
+/// Store a downloaded file of the post, unless it is a duplicate.
+async fn store_media(
+    db: &dyn Repository,
+    storage: &dyn Storage,
+    sub: &Subreddit,
+    post: &RedditPost,
+    media: &Media,
+    download: Download,
+    hash_hex: &str,
+) -> Result<(), ProcessingError> {
+    let hash = download.hash;
+    if storage.exists(hash_hex).await? {
         return Err(ProcessingError::Duplicate);
     }
 
-    std::fs::write(path, &body)?;
+    // Hosts may label files as anything, so only trust what they look like.
+    let content_type = crate::utils::sniff_file_content_type(&download.path)
+        .await?
+        .ok_or(ProcessingError::UnsupportedFormat)?;
+    if !sub.accepts_content_type(content_type) {
+        return Err(ProcessingError::InvalidContentType);
+    }
+
+    // The hash of the original is kept, such that it is still found to be a
+    // duplicate when fetched again.
+    if let Some(ref audio_base) = media.audio_base {
+        // A video without sound is better than none at all.
+        if let Err(e) = crate::video::mux_audio(&download.path, audio_base).await {
+            warn!("Could not add audio to video {}: {}", media.url, e);
+        }
+    }
+    if !*KEEP_ORIGINALS {
+        crate::strip::strip_metadata(content_type, &download.path).await?;
+    }
+
+    // Reposts are rarely the exact same file, but look the same.
+    let phash = crate::phash::compute(content_type, &download.path).await;
+    let duplicate_of = match phash {
+        Some(phash) if *MODE != Mode::Off => crate::phash::find_near_duplicate(db, phash).await?,
+        _ => None,
+    };
+    if let (Some(of), Mode::Reject) = (duplicate_of, *MODE) {
+        return Err(ProcessingError::NearDuplicate { of });
+    }
+
+    let media_info = crate::media::probe(content_type, &download.path).await;
+    storage.put_file(hash_hex, &download.path).await?;
 
-    sqlx::query(
-        "INSERT INTO birbs (hash, permalink, source_url, content_type) VALUES (?, ?, ?, ?)",
-    )
-    .bind(hash)
-    .bind(&post.permalink)
-    .bind(&post.url)
-    .bind(content_type)
-    .execute(db)
-    .await?;
+    let insert = db
+        .insert_image(NewImage {
+            hash: &hash,
+            permalink: &post.permalink,
+            source_url: &media.url,
+            content_type,
+            subreddit: &post.subreddit,
+            title: &post.title,
+            author: &post.author,
+            posted_at: post.created_utc as i64,
+            score: post.score,
+            fetched_at: Utc::now().timestamp(),
+            gallery_index: media.gallery_index,
+            verified: sub.auto_verify,
+            phash,
+            duplicate_of,
+            media: media_info,
+        })
+        .await;
+    let id = match insert {
+        Ok(id) => id,
+        Err(e) => {
+            // Don't leave an orphan behind; it would be treated as a duplicate
+            // forever. Another process may have stored the same file, though,
+            // in which case it is no orphan.
+            match db.image_id_by_hash(&hash).await {
+                Ok(None) => {
+                    if let Err(e) = storage.delete(hash_hex).await {
+                        warn!("Could not delete orphaned image {}: {}", hash_hex, e);
+                    }
+                }
+                Ok(Some(_)) => (),
+                Err(e) => warn!("Could not check whether {} is orphaned: {}", hash_hex, e),
+            }
+            return Err(e.into());
+        }
+    };
+    if let Some(phash) = phash {
+        crate::phash::remember(id, phash).await;
+    }
 
     Ok(())
 }
 
 pub async fn process_checking(
-    db: &MySqlPool,
+    db: &dyn Repository,
     id: u32,
     permalink: &str,
 ) -> Result<(), CheckingError> {
@@ -106,10 +300,7 @@ pub async fn process_checking(
     let post = match post {
         Err(RedditError::NoPost) => {
             info!("Banning post {} ({}) due to NoPost", id, permalink);
-            sqlx::query("UPDATE `birbs` SET `banned` = true, `verified` = false WHERE `id` = ?")
-                .bind(id)
-                .execute(db)
-                .await?;
+            db.set_banned(id).await?;
             return Ok(());
         }
         Err(e) => return Err(e.into()),
@@ -123,10 +314,7 @@ pub async fn process_checking(
         || post.hidden
     {
         info!("Banning post {} ({}) due to failed check", id, permalink);
-        sqlx::query("UPDATE `birbs` SET `banned` = true, `verified` = false WHERE `id` = ?")
-            .bind(id)
-            .execute(db)
-            .await?;
+        db.set_banned(id).await?;
     } else if post.score >= 128
         || Utc
             .timestamp(post.created as i64, 0)
@@ -137,10 +325,7 @@ pub async fn process_checking(
     {
         // This is very likely safe to verify.
         info!("Verifying post {} ({})", id, permalink);
-        sqlx::query("UPDATE `birbs` SET `verified` = true, `banned` = false WHERE `id` = ?")
-            .bind(id)
-            .execute(db)
-            .await?;
+        db.set_verified(id).await?;
     }
 
     Ok(())
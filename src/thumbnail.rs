@@ -0,0 +1,168 @@
+// birbfetcher - Collect bird images with ease.
+// Copyright (C) 2020-2021 Mariell Hoversholm
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::prelude::*;
+use crate::storage::Storage;
+use bytes::Bytes;
+use futures::lock::Mutex;
+use image::codecs::jpeg::JpegEncoder;
+use image::ImageFormat;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::collections::HashMap;
+use strum_macros::Display;
+
+/// The width of images served by `GET /thumb/:id` unless asked otherwise.
+pub const THUMBNAIL_WIDTH: u32 = 320;
+
+/// The widths variants are generated in.
+///
+/// A requested width is rounded up to the nearest of these, such that only a
+/// handful of variants are ever stored per image.
+const WIDTHS: &[u32] = &[160, 320, 640, 1280];
+
+/// The quality to encode lossy variants with, from 0 to 100.
+const QUALITY: u8 = 80;
+
+/// A lock for every variant being generated right now, by their keys.
+///
+/// Requests for a variant which is being generated wait for it, rather than
+/// generating it as well.
+static GENERATING: Lazy<Mutex<HashMap<String, Arc<Mutex<()>>>>> = Lazy::new(Default::default);
+
+/// The format to encode a variant in.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default, Display, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    #[strum(serialize = "jpeg")]
+    Jpeg,
+
+    #[strum(serialize = "webp")]
+    Webp,
+}
+
+impl Format {
+    /// The content type of images in this format.
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Webp => "image/webp",
+        }
+    }
+}
+
+/// Round a requested width to one variants are generated in.
+pub fn width(requested: u32) -> u32 {
+    WIDTHS
+        .iter()
+        .copied()
+        .find(|&width| width >= requested)
+        .unwrap_or(WIDTHS[WIDTHS.len() - 1])
+}
+
+/// Check whether variants can be generated from images of this content type.
+///
+/// Animated images only keep their first frame, while videos can not be
+/// resized at all.
+pub fn supported(content_type: &str) -> bool {
+    ImageFormat::from_mime_type(content_type).is_some()
+}
+
+/// Get the storage key of a variant of the image with the given hash.
+pub fn key(hash: &str, width: u32, format: Format) -> String {
+    format!("{}_w{}.{}", hash, width, format)
+}
+
+/// Generate a variant of the image with the given hash and store it, unless it
+/// already exists.
+pub async fn generate(
+    storage: &dyn Storage,
+    hash: &str,
+    width: u32,
+    format: Format,
+) -> Result<(), ThumbnailError> {
+    let key = key(hash, width, format);
+    let lock = GENERATING
+        .lock()
+        .await
+        .entry(key.clone())
+        .or_default()
+        .clone();
+    let result = {
+        let _generating = lock.lock().await;
+        generate_locked(storage, hash, &key, width, format).await
+    };
+
+    // Nobody else is waiting for the variant if only the map holds the lock.
+    let mut generating = GENERATING.lock().await;
+    if Arc::strong_count(&lock) <= 2 {
+        generating.remove(&key);
+    }
+    result
+}
+
+async fn generate_locked(
+    storage: &dyn Storage,
+    hash: &str,
+    key: &str,
+    width: u32,
+    format: Format,
+) -> Result<(), ThumbnailError> {
+    if storage.exists(key).await? {
+        return Ok(());
+    }
+
+    debug!(
+        "Generating {} variant of {} at width {}",
+        format, hash, width
+    );
+    let original = storage.get(hash).await?;
+    let data = tokio::task::spawn_blocking(move || resize(&original, width, format))
+        .await
+        .map_err(|e| ThumbnailError::Join(e.to_string()))??;
+    storage.put(key, data).await?;
+
+    Ok(())
+}
+
+/// Scale the image down to the given width and encode it in the format.
+///
+/// Images narrower than the width keep their size, but are still encoded in
+/// the format.
+fn resize(original: &[u8], width: u32, format: Format) -> Result<Bytes, ThumbnailError> {
+    let mut image = image::load_from_memory(original)?;
+    if image.width() > width {
+        image = image.thumbnail(width, u32::MAX);
+    }
+
+    let mut data = Vec::new();
+    match format {
+        Format::Jpeg => {
+            JpegEncoder::new_with_quality(&mut data, QUALITY).encode_image(&image.to_rgb8())?
+        }
+        Format::Webp => {
+            // The `image` crate can only encode lossless WebP, which is hardly
+            // smaller than the original.
+            let image = image.to_rgba8();
+            let encoded = webp::Encoder::from_rgba(&image, image.width(), image.height())
+                .encode(f32::from(QUALITY));
+            data.extend_from_slice(&encoded);
+        }
+    }
+
+    Ok(data.into())
+}
@@ -15,6 +15,9 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use sha2::Digest as _;
+use std::io;
+use std::path::Path;
+use tokio::io::AsyncReadExt as _;
 
 /// Content-Type to extension map.
 pub static CONTENT_TYPE_EXTENSIONS: phf::Map<&'static str, &'static str> = phf::phf_map! {
@@ -22,11 +25,132 @@ pub static CONTENT_TYPE_EXTENSIONS: phf::Map<&'static str, &'static str> = phf::
     "image/jpg" => "jpeg",
     "image/png" => "png",
     "image/gif" => "gifv",
+    "image/webp" => "webp",
     "video/webm" => "webm",
+    "video/mp4" => "mp4",
 };
 
-pub fn sha256(block: impl FnOnce(&mut sha2::Sha256) -> ()) -> Vec<u8> {
+/// Content-Types which are animated.
+pub const ANIMATED_CONTENT_TYPES: &[&str] = &["image/gif", "video/webm", "video/mp4"];
+
+/// The major brands of ISO base media files which are MP4 videos.
+///
+/// Others, such as AVIF and HEIC images or QuickTime movies, share the
+/// container but are nothing we can serve as `video/mp4`.
+const MP4_BRANDS: &[&[u8]] = &[
+    b"isom", b"iso2", b"iso4", b"iso5", b"iso6", b"mp41", b"mp42", b"avc1", b"dash", b"M4V ",
+];
+
+/// How many leading bytes of a file are enough to detect its content type.
+const SNIFF_LEN: u64 = 128;
+
+/// Detect the content type of a file from its leading bytes.
+///
+/// Only the formats in [`CONTENT_TYPE_EXTENSIONS`] are detected, such that we
+/// never store anything else regardless of what its host labels it as.
+pub fn sniff_content_type(data: &[u8]) -> Option<&'static str> {
+    match data {
+        [0xFF, 0xD8, 0xFF, ..] => Some("image/jpeg"),
+        [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n', ..] => Some("image/png"),
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        [_, _, _, _, b'f', b't', b'y', b'p', rest @ ..]
+            if MP4_BRANDS.iter().any(|brand| rest.starts_with(brand)) =>
+        {
+            Some("video/mp4")
+        }
+        // Matroska shares its header with WebM, which names itself shortly after.
+        [0x1A, 0x45, 0xDF, 0xA3, rest @ ..]
+            if rest[..rest.len().min(64)].windows(4).any(|w| w == b"webm") =>
+        {
+            Some("video/webm")
+        }
+        _ => None,
+    }
+}
+
+/// Detect the content type of the file at the path from its leading bytes, as
+/// `sniff_content_type` does.
+pub async fn sniff_file_content_type(path: &Path) -> io::Result<Option<&'static str>> {
+    let mut head = Vec::with_capacity(SNIFF_LEN as usize);
+    tokio::fs::File::open(path)
+        .await?
+        .take(SNIFF_LEN)
+        .read_to_end(&mut head)
+        .await?;
+    Ok(sniff_content_type(&head))
+}
+
+pub fn sha256(block: impl FnOnce(&mut sha2::Sha256)) -> Vec<u8> {
     let mut sha = sha2::Sha256::new();
     block(&mut sha);
-    sha.finalize().iter().map(|&b| b).collect()
+    sha.finalize().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The start of an EBML header with the given document type.
+    fn ebml(doc_type: &[u8]) -> Vec<u8> {
+        let mut data = vec![0x1A, 0x45, 0xDF, 0xA3, 0x9F, 0x42, 0x86, 0x81, 0x01];
+        data.extend_from_slice(&[0x42, 0x82, 0x80 | doc_type.len() as u8]);
+        data.extend_from_slice(doc_type);
+        data
+    }
+
+    #[test]
+    fn content_types_are_sniffed() {
+        let cases: &[(&[u8], Option<&str>)] = &[
+            (b"\xFF\xD8\xFF\xE0\x00\x10JFIF\x00", Some("image/jpeg")),
+            (b"\x89PNG\r\n\x1A\n\x00\x00\x00\x0DIHDR", Some("image/png")),
+            (b"GIF87a\x01\x00\x01\x00", Some("image/gif")),
+            (b"GIF89a\x01\x00\x01\x00", Some("image/gif")),
+            (b"RIFF\x24\x00\x00\x00WEBPVP8 ", Some("image/webp")),
+            (b"\x00\x00\x00\x20ftypisom", Some("video/mp4")),
+            (b"\x00\x00\x00\x18ftypmp42", Some("video/mp4")),
+            (b"\x00\x00\x00\x1CftypM4V ", Some("video/mp4")),
+            // Other ISO base media files aren't videos we serve.
+            (b"\x00\x00\x00\x1Cftypavif", None),
+            (b"\x00\x00\x00\x18ftypheic", None),
+            (b"\x00\x00\x00\x14ftypqt  ", None),
+            (b"RIFF\x24\x00\x00\x00WAVEfmt ", None),
+            (b"GIF88a\x01\x00", None),
+            (b"<!DOCTYPE html>", None),
+            (b"", None),
+        ];
+        for (data, expected) in cases {
+            assert_eq!(sniff_content_type(data), *expected, "{:?}", data);
+        }
+    }
+
+    #[test]
+    fn webm_is_told_apart_from_matroska() {
+        assert_eq!(sniff_content_type(&ebml(b"webm")), Some("video/webm"));
+        assert_eq!(sniff_content_type(&ebml(b"matroska")), None);
+
+        // The document type is near the start of the header, if anywhere.
+        let mut late = ebml(b"matroska");
+        late.extend_from_slice(&[0; 64]);
+        late.extend_from_slice(b"webm");
+        assert_eq!(sniff_content_type(&late), None);
+    }
+
+    #[test]
+    fn short_data_is_not_sniffed() {
+        let signatures: &[&[u8]] = &[
+            b"\xFF\xD8\xFF",
+            b"\x89PNG\r\n\x1A\n",
+            b"GIF89a",
+            b"RIFF\x24\x00\x00\x00WEBP",
+            b"\x00\x00\x00\x20ftypisom",
+        ];
+        for signature in signatures {
+            for len in 0..signature.len() {
+                let data = &signature[..len];
+                assert_eq!(sniff_content_type(data), None, "{:?}", data);
+            }
+        }
+        assert_eq!(sniff_content_type(&ebml(b"webm")[..12]), None);
+    }
 }
@@ -0,0 +1,171 @@
+// birbfetcher - Collect bird images with ease.
+// Copyright (C) 2020-2021 Mariell Hoversholm
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::database::MediaInfo;
+use crate::download::MAX_SIZE;
+use crate::prelude::*;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::env;
+use std::path::Path;
+use tempfile::{NamedTempFile, TempPath};
+use tokio::process::Command;
+
+/// The names Reddit has given the audio tracks of its videos over the years,
+/// newest first.
+const AUDIO_TRACKS: &[&str] = &[
+    "DASH_AUDIO_128.mp4",
+    "DASH_AUDIO_64.mp4",
+    "DASH_audio.mp4",
+    "audio",
+];
+
+/// The ffmpeg binary to mux audio with, set by `FFMPEG`.
+///
+/// Videos are stored without audio if this is not set.
+static FFMPEG: Lazy<Option<String>> = Lazy::new(|| env::var("FFMPEG").ok());
+
+/// The ffprobe binary to read the media info of videos with, set by `FFPROBE`.
+///
+/// Videos are stored without their dimensions, frames and duration if this is
+/// not set.
+static FFPROBE: Lazy<Option<String>> = Lazy::new(|| env::var("FFPROBE").ok());
+
+/// Add the audio track of a Reddit video to the video file.
+///
+/// Reddit serves the video and audio of its videos as separate files, next to
+/// each other under `base_url`. The file is left as is if muxing is disabled
+/// or the video has no audio track after all. Returns whether audio was added.
+pub async fn mux_audio(video: &Path, base_url: &str) -> Result<bool, VideoError> {
+    let ffmpeg = match *FFMPEG {
+        Some(ref ffmpeg) => ffmpeg,
+        None => return Ok(false),
+    };
+    let dir = tempfile::tempdir()?;
+    let audio = match fetch_audio(base_url, dir.path()).await? {
+        Some(audio) => audio,
+        None => {
+            debug!("No audio track found for {}", base_url);
+            return Ok(false);
+        }
+    };
+    let output_path = dir.path().join("output.mp4");
+
+    trace!("Muxing audio into {}...", base_url);
+    let output = Command::new(ffmpeg)
+        .arg("-nostdin")
+        .args(["-loglevel", "error"])
+        .arg("-i")
+        .arg(video)
+        .arg("-i")
+        .arg(&audio)
+        .args(["-map", "0:v:0", "-map", "1:a:0", "-c", "copy"])
+        .args(["-movflags", "+faststart"])
+        .arg(&output_path)
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(VideoError::Ffmpeg(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
+    // Both tracks are within the limit, but not necessarily together.
+    if tokio::fs::metadata(&output_path).await?.len() > *MAX_SIZE {
+        return Err(VideoError::TooLarge(*MAX_SIZE));
+    }
+
+    tokio::fs::copy(&output_path, video).await?;
+    Ok(true)
+}
+
+/// Download the audio track of the video at the base URL into the directory,
+/// if there is one.
+async fn fetch_audio(base_url: &str, dir: &Path) -> Result<Option<TempPath>, VideoError> {
+    for track in AUDIO_TRACKS {
+        let url = format!("{}/{}", base_url.trim_end_matches('/'), track);
+        let file = NamedTempFile::new_in(dir)?;
+        match crate::download::download(crate::REQWEST_CLIENT.get(&url), file).await {
+            Ok(audio) => return Ok(Some(audio.path)),
+            Err(DownloadError::Unsuccessful(_)) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(None)
+}
+
+/// Read the dimensions, frame count and duration of a video file.
+///
+/// Nothing is read if probing is disabled.
+pub async fn probe(video: &Path) -> Result<MediaInfo, VideoError> {
+    let ffprobe = match *FFPROBE {
+        Some(ref ffprobe) => ffprobe,
+        None => return Ok(MediaInfo::default()),
+    };
+
+    #[derive(Deserialize)]
+    struct Output {
+        #[serde(default)]
+        streams: Vec<Stream>,
+        format: Format,
+    }
+
+    #[derive(Deserialize)]
+    struct Stream {
+        width: Option<u32>,
+        height: Option<u32>,
+        // ffprobe gives most numbers as strings.
+        nb_read_packets: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    struct Format {
+        duration: Option<String>,
+    }
+
+    let output = Command::new(ffprobe)
+        .args(["-loglevel", "error"])
+        .args(["-select_streams", "v:0", "-count_packets"])
+        .args([
+            "-show_entries",
+            "stream=width,height,nb_read_packets:format=duration",
+        ])
+        .args(["-of", "json"])
+        .arg(video)
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(VideoError::Ffmpeg(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
+
+    let output: Output = serde_json::from_slice(&output.stdout)?;
+    let stream = output.streams.into_iter().next();
+    Ok(MediaInfo {
+        width: stream.as_ref().and_then(|s| s.width),
+        height: stream.as_ref().and_then(|s| s.height),
+        frames: stream
+            .and_then(|s| s.nb_read_packets)
+            .and_then(|n| n.parse().ok()),
+        duration_ms: output
+            .format
+            .duration
+            .and_then(|d| d.parse::<f64>().ok())
+            .map(|d| (d * 1000.0).round() as u64),
+        ..Default::default()
+    })
+}